@@ -0,0 +1,330 @@
+// Path: src/ambient.rs
+//
+// Samples the light sensor from the timer interrupt at a fixed rate, so the
+// filtering doesn't depend on the refresh rate and the ADC is never read from
+// the render loop. Each sample goes through a short median filter against
+// spikes and an exponential moving average, and the result is published for
+// the main loop to pick up.
+//
+// The raw samples are also checked for sensor faults. Now and then the analog
+// input is read once more with the pad's pull-up on: a connected sensor holds
+// the pin down, an open input follows the pull-up to the top of the range. A
+// working sensor also never reads exactly the same value for long because of
+// the ADC noise, unless it sits at either end of the range in the dark or in
+// full sun. Any sensor that gives no readings at all is faulty as well.
+//
+// The chip temperature and the supply voltage are read here too, since this
+// owns the ADC.
+use crate::board::{self, LightSensor, LightSensorInput, SupplyInput, SupplySensor};
+use crate::brightness::BrightnessConfig;
+use crate::bsp::hal::adc::{Adc, TempSense};
+use crate::bsp::hal::pac::{self, interrupt};
+use crate::bsp::hal::timer::{Alarm, Alarm0, Instant};
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use cortex_m::interrupt::Mutex;
+use embedded_hal::adc::OneShot;
+use fugit::ExtU32;
+
+const SAMPLE_PERIOD_US: u32 = 10_000;
+const MEDIAN_WINDOW: usize = 5;
+const ADC_MAX: f32 = 4095.0;
+// Analog readings this close to either end of the ADC range are what a working
+// sensor gives in the dark or in full sun, so they may stay the same for long.
+const RAIL_COUNTS: f32 = 8.0;
+// Every this many samples the analog input is checked for being open, about
+// once a second.
+const OPEN_CHECK_EVERY: u32 = 100;
+// The temperature is read every this many samples and averaged over a few
+// seconds, the sensor is noisy and the temperature changes slowly.
+const TEMPERATURE_EVERY: u32 = 10;
+const TEMPERATURE_SMOOTHING_S: f32 = 5.0;
+// The supply voltage is read with every sample and only smoothed a little, a
+// sagging supply has to be caught quickly.
+const SUPPLY_SMOOTHING_S: f32 = 0.05;
+
+static SAMPLER: Mutex<RefCell<Option<Sampler>>> = Mutex::new(RefCell::new(None));
+// The filtered ambient value and the smoothing time constant, as f32 bits.
+static AMBIENT: AtomicU32 = AtomicU32::new(0);
+static AMBIENT_VALID: AtomicBool = AtomicBool::new(false);
+static SMOOTHING_S: AtomicU32 = AtomicU32::new(0);
+static FAULT_TIMEOUT_S: AtomicU32 = AtomicU32::new(0);
+static FAULT: AtomicBool = AtomicBool::new(false);
+// Chip temperature in °C, as f32 bits.
+static TEMPERATURE: AtomicU32 = AtomicU32::new(0);
+static TEMPERATURE_VALID: AtomicBool = AtomicBool::new(false);
+// Supply voltage in volts, as f32 bits.
+static SUPPLY: AtomicU32 = AtomicU32::new(0);
+static SUPPLY_VALID: AtomicBool = AtomicBool::new(false);
+
+struct Sampler {
+    adc: Adc,
+    light_sensor: LightSensor,
+    alarm: Alarm0,
+    next_sample: Instant,
+    filter: AmbientFilter,
+    faults: FaultDetector,
+    temp_sense: TempSense,
+    temperature: Option<f32>,
+    supply_sensor: SupplySensor,
+    supply: Option<f32>,
+    samples: u32,
+}
+
+struct AmbientFilter {
+    window: [f32; MEDIAN_WINDOW],
+    len: usize,
+    next: usize,
+    average: Option<f32>,
+}
+
+impl AmbientFilter {
+    const fn new() -> AmbientFilter {
+        AmbientFilter {
+            window: [0.0; MEDIAN_WINDOW],
+            len: 0,
+            next: 0,
+            average: None,
+        }
+    }
+
+    fn push(&mut self, sample: f32, smoothing_s: f32) -> f32 {
+        self.window[self.next] = sample;
+        self.next = (self.next + 1) % MEDIAN_WINDOW;
+        self.len = (self.len + 1).min(MEDIAN_WINDOW);
+
+        let mut sorted = self.window;
+        let sorted = &mut sorted[..self.len];
+        sorted.sort_unstable_by(f32::total_cmp);
+        let median = sorted[self.len / 2];
+
+        let dt = SAMPLE_PERIOD_US as f32 / 1_000_000.0;
+        let average = smooth(self.average, median, dt, smoothing_s);
+        self.average = Some(average);
+        average
+    }
+}
+
+struct FaultDetector {
+    last_connected_us: u64,
+    last_change_us: u64,
+    last_sample: Option<f32>,
+}
+
+impl FaultDetector {
+    const fn new(now_us: u64) -> FaultDetector {
+        FaultDetector {
+            last_connected_us: now_us,
+            last_change_us: now_us,
+            last_sample: None,
+        }
+    }
+
+    // Takes the raw sample, None if there was no reading, and the reading with
+    // the pull-up on if one was taken. Returns whether the sensor is faulty. A
+    // timeout of 0 turns the detection off.
+    fn push(
+        &mut self,
+        sample: Option<f32>,
+        pulled_up: Option<f32>,
+        now_us: u64,
+        timeout_s: u32,
+    ) -> bool {
+        if let Some(sample) = sample {
+            let at_rail = sample <= RAIL_COUNTS || sample >= ADC_MAX - RAIL_COUNTS;
+            if board::LIGHT_SENSOR_LUX {
+                // Lux readings from I2C sensors are only checked for being there
+                self.last_connected_us = now_us;
+            } else if let Some(pulled_up) = pulled_up {
+                // Only an open input jumps to the top. A sensor in full sun
+                // reads the top already.
+                if sample >= ADC_MAX - RAIL_COUNTS || pulled_up < ADC_MAX - RAIL_COUNTS {
+                    self.last_connected_us = now_us;
+                }
+            }
+            if board::LIGHT_SENSOR_LUX || at_rail || self.last_sample != Some(sample) {
+                self.last_change_us = now_us;
+            }
+            self.last_sample = Some(sample);
+        }
+
+        let timeout_us = timeout_s as u64 * 1_000_000;
+        board::HAS_LIGHT_SENSOR
+            && timeout_us > 0
+            && (now_us - self.last_connected_us >= timeout_us
+                || now_us - self.last_change_us >= timeout_us)
+    }
+}
+
+// Maps raw ADC readings of the analog sensor to approximate lux, along the
+// straight line through a dark and a bright reference reading. A
+// phototransistor's current is close to proportional to the light.
+#[derive(Clone, Copy, Debug, Default, PartialEq, defmt::Format)]
+pub struct Calibration {
+    pub dark: Option<CalibrationPoint>,
+    pub bright: Option<CalibrationPoint>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub struct CalibrationPoint {
+    pub raw: f32,
+    pub lux: f32,
+}
+
+impl Calibration {
+    // The ambient value for the brightness curve. Lux sensors, and analog
+    // sensors without both usable reference readings, pass it on unchanged.
+    pub fn apply(&self, ambient: f32) -> f32 {
+        match (self.dark, self.bright) {
+            (Some(dark), Some(bright))
+                if !board::LIGHT_SENSOR_LUX && bright.raw > dark.raw && bright.lux > dark.lux =>
+            {
+                let lux_per_count = (bright.lux - dark.lux) / (bright.raw - dark.raw);
+                (dark.lux + (ambient - dark.raw) * lux_per_count).max(0.0)
+            }
+            _ => ambient,
+        }
+    }
+}
+
+// Chip temperature in °C from a raw reading of the built-in sensor, using the
+// formula from the RP2040 datasheet.
+fn temperature_c(raw: u16) -> f32 {
+    let volts = raw as f32 * 3.3 / 4096.0;
+    27.0 - (volts - 0.706) / 0.001721
+}
+
+// One step of an exponential moving average with time constant `smoothing_s`
+// over samples `dt` seconds apart.
+fn smooth(average: Option<f32>, sample: f32, dt: f32, smoothing_s: f32) -> f32 {
+    match average {
+        Some(average) if smoothing_s > 0.0 => {
+            average + (sample - average) * dt / (smoothing_s + dt)
+        }
+        _ => sample,
+    }
+}
+
+// Starts sampling. Takes over the ADC and the first timer alarm.
+pub fn start(
+    mut adc: Adc,
+    light_sensor: LightSensor,
+    supply_sensor: SupplySensor,
+    mut alarm: Alarm0,
+    now: Instant,
+) {
+    let temp_sense = adc.enable_temp_sensor();
+
+    let next_sample = now + SAMPLE_PERIOD_US.micros();
+    alarm.schedule_at(next_sample).unwrap();
+    alarm.enable_interrupt();
+
+    cortex_m::interrupt::free(|cs| {
+        SAMPLER.borrow(cs).replace(Some(Sampler {
+            adc,
+            light_sensor,
+            alarm,
+            next_sample,
+            filter: AmbientFilter::new(),
+            faults: FaultDetector::new(now.ticks()),
+            temp_sense,
+            temperature: None,
+            supply_sensor,
+            supply: None,
+            samples: 0,
+        }));
+    });
+
+    unsafe {
+        pac::NVIC::unmask(pac::Interrupt::TIMER_IRQ_0);
+    }
+}
+
+// Takes the smoothing time constant and the fault timeout from `config`.
+pub fn configure(config: &BrightnessConfig) {
+    SMOOTHING_S.store(config.smoothing_s.to_bits(), Ordering::Relaxed);
+    FAULT_TIMEOUT_S.store(config.fault_timeout_s as u32, Ordering::Relaxed);
+}
+
+// The latest filtered ambient value, None until the first sample or without a
+// sensor.
+pub fn ambient() -> Option<f32> {
+    AMBIENT_VALID
+        .load(Ordering::Acquire)
+        .then(|| f32::from_bits(AMBIENT.load(Ordering::Relaxed)))
+}
+
+// The smoothed chip temperature in °C, None until the first reading.
+pub fn temperature() -> Option<f32> {
+    TEMPERATURE_VALID
+        .load(Ordering::Acquire)
+        .then(|| f32::from_bits(TEMPERATURE.load(Ordering::Relaxed)))
+}
+
+// The smoothed supply voltage in volts, None on boards that don't measure it.
+pub fn supply_voltage() -> Option<f32> {
+    SUPPLY_VALID
+        .load(Ordering::Acquire)
+        .then(|| f32::from_bits(SUPPLY.load(Ordering::Relaxed)))
+}
+
+// Whether the sensor looks disconnected, shorted or stuck.
+pub fn fault() -> bool {
+    FAULT.load(Ordering::Relaxed)
+}
+
+#[interrupt]
+fn TIMER_IRQ_0() {
+    cortex_m::interrupt::free(|cs| {
+        let mut sampler = SAMPLER.borrow(cs).borrow_mut();
+        let Some(sampler) = sampler.as_mut() else {
+            return;
+        };
+
+        sampler.alarm.clear_interrupt();
+        let now_us = sampler.next_sample.ticks();
+        // Scheduling from the last sample time keeps the rate exact. A time
+        // that has passed already fires right away.
+        sampler.next_sample += SAMPLE_PERIOD_US.micros();
+        sampler.alarm.schedule_at(sampler.next_sample).unwrap();
+
+        let sample = sampler.light_sensor.read(&mut sampler.adc, now_us);
+        let pulled_up = if sampler.samples.is_multiple_of(OPEN_CHECK_EVERY) {
+            sampler.light_sensor.read_pulled_up(&mut sampler.adc)
+        } else {
+            None
+        };
+        let timeout_s = FAULT_TIMEOUT_S.load(Ordering::Relaxed);
+        let fault = sampler.faults.push(sample, pulled_up, now_us, timeout_s);
+        FAULT.store(fault, Ordering::Relaxed);
+
+        if let Some(sample) = sample {
+            let smoothing_s = f32::from_bits(SMOOTHING_S.load(Ordering::Relaxed));
+            let ambient = sampler.filter.push(sample, smoothing_s);
+            AMBIENT.store(ambient.to_bits(), Ordering::Relaxed);
+            AMBIENT_VALID.store(true, Ordering::Release);
+        }
+
+        if sampler.samples.is_multiple_of(TEMPERATURE_EVERY) {
+            let raw: u16 = sampler.adc.read(&mut sampler.temp_sense).unwrap();
+            let sample = temperature_c(raw);
+            let dt = (TEMPERATURE_EVERY * SAMPLE_PERIOD_US) as f32 / 1_000_000.0;
+            let temperature = match sampler.temperature {
+                Some(average) => average + (sample - average) * dt / (TEMPERATURE_SMOOTHING_S + dt),
+                None => sample,
+            };
+            sampler.temperature = Some(temperature);
+            TEMPERATURE.store(temperature.to_bits(), Ordering::Relaxed);
+            TEMPERATURE_VALID.store(true, Ordering::Release);
+        }
+
+        if let Some(sample) = sampler.supply_sensor.read_volts(&mut sampler.adc) {
+            let dt = SAMPLE_PERIOD_US as f32 / 1_000_000.0;
+            let supply = smooth(sampler.supply, sample, dt, SUPPLY_SMOOTHING_S);
+            sampler.supply = Some(supply);
+            SUPPLY.store(supply.to_bits(), Ordering::Relaxed);
+            SUPPLY_VALID.store(true, Ordering::Release);
+        }
+        sampler.samples = sampler.samples.wrapping_add(1);
+    });
+}
@@ -0,0 +1,508 @@
+// Path: src/board.rs
+//
+// Pin mapping for the boards the firmware runs on. The profile is picked with a
+// cargo feature (see Cargo.toml); without one the custom matrix controller
+// pinout is used. If several board features are enabled the first one in the
+// order below wins, so `--all-features` builds still compile.
+use crate::bsp;
+use crate::bsp::hal;
+use crate::bsp::hal::adc::Adc;
+use crate::bsp::hal::gpio::{
+    bank0::*, FloatingInput, FunctionI2C, FunctionSpi, Pin, PullUpInput, PushPullOutput,
+};
+use crate::bsp::hal::pac;
+use crate::rgb_matrix::{AddrPins, ClockPin, LatchPin, OutputEnablePin, RgbPins, RowAddress};
+use embedded_hal::adc::{Channel, OneShot};
+use fugit::HertzU32;
+#[cfg(any(
+    feature = "light-sensor-veml7700",
+    feature = "light-sensor-bh1750",
+    feature = "light-sensor-tsl2591"
+))]
+use rp2040_led_matrix::lux_sensors::{self, LuxSensor};
+
+type Out<I> = Pin<I, PushPullOutput>;
+
+// Interstate 75 and Interstate 75 W share the same pinout. HUB75 is on
+// gpio0-13, the SPI input uses the analog header pins (gpio26 SCK, gpio27 TX,
+// gpio28 RX) on SPI1, so there is no analog light sensor on this board. I2C
+// light sensors go on the Qw/ST connector (gpio20 SDA, gpio21 SCL) and button
+// A (gpio14) selects the test patterns. The supply voltage isn't measured.
+#[cfg(feature = "board-interstate75")]
+mod profile {
+    use super::*;
+
+    pub type R0 = Out<Gpio0>;
+    pub type G0 = Out<Gpio1>;
+    pub type B0 = Out<Gpio2>;
+    pub type R1 = Out<Gpio3>;
+    pub type G1 = Out<Gpio4>;
+    pub type B1 = Out<Gpio5>;
+    pub type A = Out<Gpio6>;
+    pub type B = Out<Gpio7>;
+    pub type C = Out<Gpio8>;
+    pub type D = Out<Gpio9>;
+    pub type E = Out<Gpio10>;
+    pub type Clk = Out<Gpio11>;
+    pub type Lat = Out<Gpio12>;
+    pub type Oe = Out<Gpio13>;
+
+    pub type InputSpiDevice = pac::SPI1;
+    pub type InputPins = (
+        Pin<Gpio28, FunctionSpi>,
+        Pin<Gpio27, FunctionSpi>,
+        Pin<Gpio26, FunctionSpi>,
+    );
+    pub type AnalogLightSensor = NoLightSensor;
+    pub type SupplySensor = NoSupplySensor;
+    pub type PanelPower = NoPanelPower;
+    pub type I2cDevice = pac::I2C0;
+    pub type I2cPins = (Pin<Gpio20, FunctionI2C>, Pin<Gpio21, FunctionI2C>);
+    pub type TestButton = Pin<Gpio14, PullUpInput>;
+
+    pub fn split(pins: bsp::Pins) -> Board {
+        Board {
+            rgb_pins: RgbPins::new(
+                pins.gpio0.into_push_pull_output(),
+                pins.gpio1.into_push_pull_output(),
+                pins.gpio2.into_push_pull_output(),
+                pins.gpio3.into_push_pull_output(),
+                pins.gpio4.into_push_pull_output(),
+                pins.gpio5.into_push_pull_output(),
+            ),
+            addr_pins: AddrPins::new(
+                pins.gpio6.into_push_pull_output(),
+                pins.gpio7.into_push_pull_output(),
+                pins.gpio8.into_push_pull_output(),
+                pins.gpio9.into_push_pull_output(),
+                pins.gpio10.into_push_pull_output(),
+            ),
+            clock: ClockPin::new(pins.gpio11.into_push_pull_output()),
+            latch: LatchPin::new(pins.gpio12.into_push_pull_output()),
+            output_enable: OutputEnablePin::new(pins.gpio13.into_push_pull_output()),
+            input_pins: (
+                pins.gpio28.into_mode(),
+                pins.gpio27.into_mode(),
+                pins.gpio26.into_mode(),
+            ),
+            analog_light_sensor: NoLightSensor,
+            supply_sensor: NoSupplySensor,
+            panel_power: NoPanelPower,
+            i2c_pins: (pins.gpio20.into_mode(), pins.gpio21.into_mode()),
+            test_button: pins.gpio14.into_pull_up_input(),
+        }
+    }
+
+    pub fn input_spi_device(_spi0: pac::SPI0, spi1: pac::SPI1) -> InputSpiDevice {
+        spi1
+    }
+
+    pub fn i2c_device(i2c0: pac::I2C0, _i2c1: pac::I2C1) -> I2cDevice {
+        i2c0
+    }
+}
+
+// Adafruit Feather RP2040 with the RGB Matrix FeatherWing, wired the way the
+// Protomatter library expects. The wing only has A-D, so E has to be routed to
+// gpio6 by hand for 1/32 scan panels. The SPI input uses the Feather SPI pins
+// (gpio20 RX, gpio18 SCK, gpio19 TX), the light sensor sits on A0 (gpio26)
+// or the STEMMA QT connector (gpio2 SDA, gpio3 SCL) and a test pattern button
+// on A1 (gpio27). gpio29 drives row address C, so the supply voltage isn't
+// measured.
+#[cfg(all(
+    feature = "board-adafruit-feather",
+    not(feature = "board-interstate75")
+))]
+mod profile {
+    use super::*;
+
+    pub type R0 = Out<Gpio8>;
+    pub type G0 = Out<Gpio7>;
+    pub type B0 = Out<Gpio9>;
+    pub type R1 = Out<Gpio11>;
+    pub type G1 = Out<Gpio10>;
+    pub type B1 = Out<Gpio12>;
+    pub type A = Out<Gpio25>;
+    pub type B = Out<Gpio24>;
+    pub type C = Out<Gpio29>;
+    pub type D = Out<Gpio28>;
+    pub type E = Out<Gpio6>;
+    pub type Clk = Out<Gpio13>;
+    pub type Lat = Out<Gpio1>;
+    pub type Oe = Out<Gpio0>;
+
+    pub type InputSpiDevice = pac::SPI0;
+    pub type InputPins = (
+        Pin<Gpio20, FunctionSpi>,
+        Pin<Gpio19, FunctionSpi>,
+        Pin<Gpio18, FunctionSpi>,
+    );
+    pub type AnalogLightSensor = Pin<Gpio26, FloatingInput>;
+    pub type SupplySensor = NoSupplySensor;
+    pub type PanelPower = NoPanelPower;
+    pub type I2cDevice = pac::I2C1;
+    pub type I2cPins = (Pin<Gpio2, FunctionI2C>, Pin<Gpio3, FunctionI2C>);
+    pub type TestButton = Pin<Gpio27, PullUpInput>;
+
+    pub fn split(pins: bsp::Pins) -> Board {
+        Board {
+            rgb_pins: RgbPins::new(
+                pins.gpio8.into_push_pull_output(),
+                pins.gpio7.into_push_pull_output(),
+                pins.gpio9.into_push_pull_output(),
+                pins.gpio11.into_push_pull_output(),
+                pins.gpio10.into_push_pull_output(),
+                pins.gpio12.into_push_pull_output(),
+            ),
+            addr_pins: AddrPins::new(
+                pins.led.into_push_pull_output(),
+                pins.vbus_detect.into_push_pull_output(),
+                pins.voltage_monitor.into_push_pull_output(),
+                pins.gpio28.into_push_pull_output(),
+                pins.gpio6.into_push_pull_output(),
+            ),
+            clock: ClockPin::new(pins.gpio13.into_push_pull_output()),
+            latch: LatchPin::new(pins.gpio1.into_push_pull_output()),
+            output_enable: OutputEnablePin::new(pins.gpio0.into_push_pull_output()),
+            input_pins: (
+                pins.gpio20.into_mode(),
+                pins.gpio19.into_mode(),
+                pins.gpio18.into_mode(),
+            ),
+            analog_light_sensor: pins.gpio26.into_floating_input(),
+            supply_sensor: NoSupplySensor,
+            panel_power: NoPanelPower,
+            i2c_pins: (pins.gpio2.into_mode(), pins.gpio3.into_mode()),
+            test_button: pins.gpio27.into_pull_up_input(),
+        }
+    }
+
+    pub fn input_spi_device(spi0: pac::SPI0, _spi1: pac::SPI1) -> InputSpiDevice {
+        spi0
+    }
+
+    pub fn i2c_device(_i2c0: pac::I2C0, i2c1: pac::I2C1) -> I2cDevice {
+        i2c1
+    }
+}
+
+// Our own matrix controller: HUB75 on gpio0-13, SPI input on SPI0 (gpio16 RX,
+// gpio18 SCK, gpio19 TX), the phototransistor on gpio28, the I2C sensor header
+// on gpio20 SDA and gpio21 SCL and the test pattern jumper on gpio14. The Pico
+// divides VSYS by 3 onto gpio29. gpio22 can switch the panel supply through a
+// MOSFET or relay, high is on.
+#[cfg(not(any(feature = "board-interstate75", feature = "board-adafruit-feather")))]
+mod profile {
+    use super::*;
+
+    pub type R0 = Out<Gpio0>;
+    pub type G0 = Out<Gpio1>;
+    pub type B0 = Out<Gpio2>;
+    pub type R1 = Out<Gpio3>;
+    pub type G1 = Out<Gpio4>;
+    pub type B1 = Out<Gpio5>;
+    pub type A = Out<Gpio6>;
+    pub type B = Out<Gpio7>;
+    pub type C = Out<Gpio8>;
+    pub type D = Out<Gpio9>;
+    pub type E = Out<Gpio10>;
+    pub type Clk = Out<Gpio11>;
+    pub type Lat = Out<Gpio12>;
+    pub type Oe = Out<Gpio13>;
+
+    pub type InputSpiDevice = pac::SPI0;
+    pub type InputPins = (
+        Pin<Gpio16, FunctionSpi>,
+        Pin<Gpio19, FunctionSpi>,
+        Pin<Gpio18, FunctionSpi>,
+    );
+    pub type AnalogLightSensor = Pin<Gpio28, FloatingInput>;
+    pub type SupplySensor = Pin<Gpio29, FloatingInput>;
+    pub type PanelPower = Out<Gpio22>;
+    pub type I2cDevice = pac::I2C0;
+    pub type I2cPins = (Pin<Gpio20, FunctionI2C>, Pin<Gpio21, FunctionI2C>);
+    pub type TestButton = Pin<Gpio14, PullUpInput>;
+
+    pub fn split(pins: bsp::Pins) -> Board {
+        Board {
+            rgb_pins: RgbPins::new(
+                pins.gpio0.into_push_pull_output(),
+                pins.gpio1.into_push_pull_output(),
+                pins.gpio2.into_push_pull_output(),
+                pins.gpio3.into_push_pull_output(),
+                pins.gpio4.into_push_pull_output(),
+                pins.gpio5.into_push_pull_output(),
+            ),
+            addr_pins: AddrPins::new(
+                pins.gpio6.into_push_pull_output(),
+                pins.gpio7.into_push_pull_output(),
+                pins.gpio8.into_push_pull_output(),
+                pins.gpio9.into_push_pull_output(),
+                pins.gpio10.into_push_pull_output(),
+            ),
+            clock: ClockPin::new(pins.gpio11.into_push_pull_output()),
+            latch: LatchPin::new(pins.gpio12.into_push_pull_output()),
+            output_enable: OutputEnablePin::new(pins.gpio13.into_push_pull_output()),
+            input_pins: (
+                pins.gpio16.into_mode(),
+                pins.gpio19.into_mode(),
+                pins.gpio18.into_mode(),
+            ),
+            analog_light_sensor: pins.gpio28.into_floating_input(),
+            supply_sensor: pins.voltage_monitor.into_floating_input(),
+            panel_power: pins.gpio22.into_push_pull_output(),
+            i2c_pins: (pins.gpio20.into_mode(), pins.gpio21.into_mode()),
+            test_button: pins.gpio14.into_pull_up_input(),
+        }
+    }
+
+    pub fn input_spi_device(spi0: pac::SPI0, _spi1: pac::SPI1) -> InputSpiDevice {
+        spi0
+    }
+
+    pub fn i2c_device(i2c0: pac::I2C0, _i2c1: pac::I2C1) -> I2cDevice {
+        i2c0
+    }
+}
+
+pub use profile::{i2c_device, input_spi_device, split, PanelPower, SupplySensor};
+
+pub type MatrixRgbPins =
+    RgbPins<profile::R0, profile::G0, profile::B0, profile::R1, profile::G1, profile::B1>;
+pub type MatrixAddrPins = AddrPins<profile::A, profile::B, profile::C, profile::D, profile::E>;
+
+// Everything the firmware needs from the board, already in the right pin modes.
+pub struct Board {
+    pub rgb_pins: MatrixRgbPins,
+    pub addr_pins: MatrixAddrPins,
+    pub latch: LatchPin<profile::Lat>,
+    pub clock: ClockPin<profile::Clk>,
+    pub output_enable: OutputEnablePin<profile::Oe>,
+    pub input_pins: profile::InputPins,
+    pub analog_light_sensor: profile::AnalogLightSensor,
+    // VSYS/3 on ADC channel 3, only on Pico based boards.
+    pub supply_sensor: SupplySensor,
+    // Switches the panel supply off while idle, high is on.
+    pub panel_power: PanelPower,
+    // SDA and SCL of the I2C bus for digital light sensors.
+    pub i2c_pins: profile::I2cPins,
+    // Held low at boot to start the test patterns, pressed to step through them.
+    pub test_button: profile::TestButton,
+}
+
+// Row addressing used for the panel, picked with the `row-addr-*` cargo
+// features. Direct binary addressing on A-E is the default.
+#[cfg(feature = "row-addr-shift-register")]
+pub fn row_address(addr_pins: MatrixAddrPins) -> impl RowAddress {
+    crate::rgb_matrix::ShiftRegisterAddrPins::new(addr_pins.a, addr_pins.b)
+}
+
+#[cfg(all(feature = "row-addr-abc", not(feature = "row-addr-shift-register")))]
+pub fn row_address(addr_pins: MatrixAddrPins) -> impl RowAddress {
+    crate::rgb_matrix::AbcAddrPins::new(addr_pins.a, addr_pins.b, addr_pins.c)
+}
+
+#[cfg(not(any(feature = "row-addr-shift-register", feature = "row-addr-abc")))]
+pub fn row_address(addr_pins: MatrixAddrPins) -> impl RowAddress {
+    addr_pins
+}
+
+#[cfg(any(
+    feature = "light-sensor-veml7700",
+    feature = "light-sensor-bh1750",
+    feature = "light-sensor-tsl2591"
+))]
+pub type SensorI2c = hal::I2C<profile::I2cDevice, profile::I2cPins>;
+
+// The light sensor, picked with the `light-sensor-*` cargo features. Without
+// one the board's analog sensor is used.
+#[cfg(feature = "light-sensor-veml7700")]
+pub type LightSensor = lux_sensors::Veml7700<SensorI2c>;
+#[cfg(all(
+    feature = "light-sensor-bh1750",
+    not(feature = "light-sensor-veml7700")
+))]
+pub type LightSensor = lux_sensors::Bh1750<SensorI2c>;
+#[cfg(all(
+    feature = "light-sensor-tsl2591",
+    not(any(feature = "light-sensor-veml7700", feature = "light-sensor-bh1750"))
+))]
+pub type LightSensor = lux_sensors::Tsl2591<SensorI2c>;
+#[cfg(not(any(
+    feature = "light-sensor-veml7700",
+    feature = "light-sensor-bh1750",
+    feature = "light-sensor-tsl2591"
+)))]
+pub type LightSensor = profile::AnalogLightSensor;
+
+// Whether the light sensor reports lux rather than raw ADC counts.
+pub const LIGHT_SENSOR_LUX: bool = cfg!(any(
+    feature = "light-sensor-veml7700",
+    feature = "light-sensor-bh1750",
+    feature = "light-sensor-tsl2591"
+));
+
+#[cfg(any(
+    feature = "light-sensor-veml7700",
+    feature = "light-sensor-bh1750",
+    feature = "light-sensor-tsl2591"
+))]
+pub fn light_sensor(
+    _analog_light_sensor: profile::AnalogLightSensor,
+    i2c_pins: profile::I2cPins,
+    i2c_device: profile::I2cDevice,
+    resets: &mut pac::RESETS,
+    system_clock: HertzU32,
+) -> LightSensor {
+    use fugit::RateExtU32;
+
+    let i2c = SensorI2c::new_controller(
+        i2c_device,
+        i2c_pins.0,
+        i2c_pins.1,
+        400.kHz(),
+        resets,
+        system_clock,
+    );
+    LightSensor::new(i2c)
+}
+
+#[cfg(not(any(
+    feature = "light-sensor-veml7700",
+    feature = "light-sensor-bh1750",
+    feature = "light-sensor-tsl2591"
+)))]
+pub fn light_sensor(
+    analog_light_sensor: profile::AnalogLightSensor,
+    _i2c_pins: profile::I2cPins,
+    _i2c_device: profile::I2cDevice,
+    _resets: &mut pac::RESETS,
+    _system_clock: HertzU32,
+) -> LightSensor {
+    analog_light_sensor
+}
+
+// Whether there is a light sensor at all, only the Interstate 75 has none of
+// its own.
+pub const HAS_LIGHT_SENSOR: bool = LIGHT_SENSOR_LUX || !cfg!(feature = "board-interstate75");
+
+// Placeholder for boards without an analog light sensor.
+#[cfg(feature = "board-interstate75")]
+pub struct NoLightSensor;
+
+// Placeholder for boards without a panel power switch.
+#[cfg(any(feature = "board-interstate75", feature = "board-adafruit-feather"))]
+pub struct NoPanelPower;
+
+#[cfg(any(feature = "board-interstate75", feature = "board-adafruit-feather"))]
+impl embedded_hal::digital::v2::OutputPin for NoPanelPower {
+    type Error = core::convert::Infallible;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+// Placeholder for boards that don't measure the supply voltage.
+#[cfg(any(feature = "board-interstate75", feature = "board-adafruit-feather"))]
+pub struct NoSupplySensor;
+
+pub trait SupplyInput {
+    // The supply voltage (VSYS) in volts, None if it isn't measured.
+    fn read_volts(&mut self, adc: &mut Adc) -> Option<f32>;
+}
+
+#[cfg(any(feature = "board-interstate75", feature = "board-adafruit-feather"))]
+impl SupplyInput for NoSupplySensor {
+    fn read_volts(&mut self, _adc: &mut Adc) -> Option<f32> {
+        None
+    }
+}
+
+impl SupplyInput for Pin<Gpio29, FloatingInput> {
+    fn read_volts(&mut self, adc: &mut Adc) -> Option<f32> {
+        let raw: u16 = adc.read(self).ok()?;
+        Some(raw as f32 * 3.0 * 3.3 / 4096.0)
+    }
+}
+
+pub trait LightSensorInput {
+    // Returns the ambient light in lux, or the raw 12 bit ADC reading for
+    // analog sensors. None if there is no reading right now.
+    fn read(&mut self, adc: &mut Adc, now_us: u64) -> Option<f32>;
+
+    // A raw reading taken with the pad's pull-up briefly enabled, to tell an
+    // open input from a sensor. None for sensors that can't be checked so.
+    fn read_pulled_up(&mut self, _adc: &mut Adc) -> Option<f32> {
+        None
+    }
+}
+
+// How long the pull-up is on before the reading, in cycles. It only has to
+// charge the pad and the ADC input: 10 us at 302 MHz, longer at lower clocks.
+const PULL_UP_SETTLE_CYCLES: u32 = 3_000;
+
+#[cfg(feature = "board-interstate75")]
+impl LightSensorInput for NoLightSensor {
+    fn read(&mut self, _adc: &mut Adc, _now_us: u64) -> Option<f32> {
+        None
+    }
+}
+
+impl<I> LightSensorInput for Pin<I, FloatingInput>
+where
+    I: hal::gpio::PinId,
+    Pin<I, FloatingInput>: Channel<Adc, ID = u8>,
+{
+    fn read(&mut self, adc: &mut Adc, _now_us: u64) -> Option<f32> {
+        adc.read(self).ok().map(|value: u16| value as f32)
+    }
+
+    fn read_pulled_up(&mut self, adc: &mut Adc) -> Option<f32> {
+        // The pin stays a floating input for the HAL, only the pad changes
+        let pads = unsafe { &*pac::PADS_BANK0::ptr() };
+        let pad = &pads.gpio[I::DYN.num as usize];
+        pad.modify(|_, w| w.pue().set_bit());
+        cortex_m::asm::delay(PULL_UP_SETTLE_CYCLES);
+        let value = adc.read(self).ok().map(|value: u16| value as f32);
+        pad.modify(|_, w| w.pue().clear_bit());
+        value
+    }
+}
+
+#[cfg(any(
+    feature = "light-sensor-veml7700",
+    feature = "light-sensor-bh1750",
+    feature = "light-sensor-tsl2591"
+))]
+impl LightSensorInput for lux_sensors::Veml7700<SensorI2c> {
+    fn read(&mut self, _adc: &mut Adc, now_us: u64) -> Option<f32> {
+        self.read_lux(now_us)
+    }
+}
+
+#[cfg(any(
+    feature = "light-sensor-veml7700",
+    feature = "light-sensor-bh1750",
+    feature = "light-sensor-tsl2591"
+))]
+impl LightSensorInput for lux_sensors::Bh1750<SensorI2c> {
+    fn read(&mut self, _adc: &mut Adc, now_us: u64) -> Option<f32> {
+        self.read_lux(now_us)
+    }
+}
+
+#[cfg(any(
+    feature = "light-sensor-veml7700",
+    feature = "light-sensor-bh1750",
+    feature = "light-sensor-tsl2591"
+))]
+impl LightSensorInput for lux_sensors::Tsl2591<SensorI2c> {
+    fn read(&mut self, _adc: &mut Adc, now_us: u64) -> Option<f32> {
+        self.read_lux(now_us)
+    }
+}
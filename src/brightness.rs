@@ -0,0 +1,294 @@
+// Path: src/brightness.rs
+//
+// Turns the ambient light reading into the brightness level passed to
+// `RgbMatrix96x48::render`. The ambient value is lux for I2C sensors, and for
+// the analog sensor raw ADC counts, or approximate lux once it is calibrated
+// (see `ambient::Calibration`).
+//
+// The curve can be overridden with a fixed level or shifted by an offset, and
+// time of day schedules can cap or fix the level, e.g. for the night. Changes
+// of the level are ramped over the configured time.
+use crate::board;
+use crate::rgb_matrix::LEVEL_STEPS;
+use libm::powf;
+
+// Brightness levels go from 0 (dimmest delay table) to this.
+pub const MAX_LEVEL: u8 = 7;
+const STEPS: usize = MAX_LEVEL as usize;
+pub const SCHEDULE_ENTRIES: usize = 4;
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum Curve {
+    // Ambient values at which levels 1 to 7 start.
+    Steps([u32; STEPS]),
+    // Levels spread evenly on a log scale, level 0 below `dark` and level 7
+    // from `bright` on.
+    Logarithmic { dark: u32, bright: u32 },
+}
+
+impl Curve {
+    // Ambient values at which levels 1 to 7 start.
+    fn thresholds(&self) -> [u32; STEPS] {
+        match *self {
+            Curve::Steps(thresholds) => thresholds,
+            Curve::Logarithmic { dark, bright } => {
+                let dark = dark.max(1) as f32;
+                let ratio = bright as f32 / dark;
+                let mut thresholds = [0; STEPS];
+                for (step, threshold) in thresholds.iter_mut().enumerate() {
+                    let exponent = step as f32 / (STEPS - 1) as f32;
+                    *threshold = (dark * powf(ratio, exponent)) as u32;
+                }
+                thresholds
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub struct BrightnessConfig {
+    pub curve: Curve,
+    // The ambient value has to pass a threshold by this many percent before
+    // the level changes, so it doesn't toggle around a threshold.
+    pub hysteresis_percent: u8,
+    pub min_level: u8,
+    pub max_level: u8,
+    // Time constant of the ambient light smoothing, see `ambient`.
+    pub smoothing_s: f32,
+    // Level used while the sensor is faulty, and how long a reading has to
+    // look wrong before it counts as a fault, 0 to never fall back.
+    pub fault_level: u8,
+    pub fault_timeout_s: u16,
+    // Time a brightness change takes, 0 to change at once.
+    pub ramp_s: f32,
+}
+
+impl Default for BrightnessConfig {
+    fn default() -> Self {
+        let curve = if board::LIGHT_SENSOR_LUX {
+            Curve::Logarithmic {
+                dark: 5,
+                bright: 5000,
+            }
+        } else {
+            Curve::Steps([0, 50, 100, 200, 400, 700, 1200])
+        };
+
+        BrightnessConfig {
+            curve,
+            hysteresis_percent: 10,
+            min_level: 1,
+            max_level: MAX_LEVEL,
+            smoothing_s: 2.0,
+            fault_level: 4,
+            fault_timeout_s: 30,
+            ramp_s: 1.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, defmt::Format)]
+pub enum Mode {
+    // Follow the ambient light along the curve.
+    #[default]
+    Auto,
+    // A fixed level, whatever the light and the schedule.
+    Manual(u8),
+    // The curve, moved up or down by some levels.
+    AutoOffset(i8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum ScheduleAction {
+    Cap(u8),
+    Fix(u8),
+}
+
+// Applies from `start_minute` up to `end_minute` after midnight, over midnight
+// if the end is before the start.
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub struct ScheduleEntry {
+    pub start_minute: u16,
+    pub end_minute: u16,
+    pub action: ScheduleAction,
+}
+
+impl ScheduleEntry {
+    fn is_active(&self, minute: u16) -> bool {
+        if self.start_minute <= self.end_minute {
+            (self.start_minute..self.end_minute).contains(&minute)
+        } else {
+            minute >= self.start_minute || minute < self.end_minute
+        }
+    }
+}
+
+pub type Schedule = [Option<ScheduleEntry>; SCHEDULE_ENTRIES];
+
+pub struct BrightnessController {
+    config: BrightnessConfig,
+    thresholds: [u32; STEPS],
+    ambient: Option<f32>,
+    // Level from the curve before the min/max clamp, so the hysteresis works
+    // the same whatever the clamp.
+    curve_level: u8,
+    fault: bool,
+    mode: Mode,
+    schedule: Schedule,
+    // Minutes since midnight, None while the time isn't known.
+    minute_of_day: Option<u16>,
+    // Cap from the thermal protection, over every mode.
+    max_level: u8,
+    // Brightness ramp, in the fine steps `render` takes.
+    shown: u16,
+    ramp_from: u16,
+    ramp_to: u16,
+    ramp_start_us: u64,
+}
+
+impl BrightnessController {
+    pub fn new(config: BrightnessConfig, mode: Mode, schedule: Schedule) -> BrightnessController {
+        let mut controller = BrightnessController {
+            config,
+            thresholds: config.curve.thresholds(),
+            ambient: None,
+            // Full brightness until the first sample, and for good without a sensor
+            curve_level: MAX_LEVEL,
+            fault: false,
+            mode,
+            schedule,
+            minute_of_day: None,
+            max_level: MAX_LEVEL,
+            shown: 0,
+            ramp_from: 0,
+            ramp_to: 0,
+            ramp_start_us: 0,
+        };
+        // No ramp at boot
+        controller.shown = controller.level() as u16 * LEVEL_STEPS;
+        controller.ramp_to = controller.shown;
+        controller
+    }
+
+    pub fn set_config(&mut self, config: BrightnessConfig) {
+        self.config = config;
+        self.thresholds = config.curve.thresholds();
+    }
+
+    // Smoothed ambient value, None before the first one was set.
+    pub fn ambient(&self) -> Option<f32> {
+        self.ambient
+    }
+
+    // Updates the level from the smoothed ambient value.
+    pub fn set_ambient(&mut self, ambient: f32) {
+        self.ambient = Some(ambient);
+
+        let ambient = ambient as u64;
+        let hysteresis = self.config.hysteresis_percent.min(100) as u64;
+        let up = |threshold: u32| threshold as u64 * (100 + hysteresis) / 100;
+        let down = |threshold: u32| threshold as u64 * (100 - hysteresis) / 100;
+
+        let mut level = self.curve_level as usize;
+        while level < STEPS && ambient >= up(self.thresholds[level]) {
+            level += 1;
+        }
+        while level > 0 && ambient < down(self.thresholds[level - 1]) {
+            level -= 1;
+        }
+        self.curve_level = level as u8;
+    }
+
+    // While the sensor is faulty the configured fault level is used instead of
+    // the curve.
+    pub fn set_fault(&mut self, fault: bool) {
+        self.fault = fault;
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+    }
+
+    pub fn set_schedule(&mut self, schedule: Schedule) {
+        self.schedule = schedule;
+    }
+
+    // Schedules are only followed once the time of day is known.
+    pub fn set_minute_of_day(&mut self, minute: Option<u16>) {
+        self.minute_of_day = minute.map(|minute| minute % MINUTES_PER_DAY);
+    }
+
+    pub fn max_level(&self) -> u8 {
+        self.max_level
+    }
+
+    // Upper limit from the thermal protection, it applies in every mode.
+    pub fn set_max_level(&mut self, level: u8) {
+        self.max_level = level;
+    }
+
+    // Brightness for `render` at `now_us`, on the way to `level`. A new level
+    // during a ramp starts a new one from where the old one got to.
+    pub fn output(&mut self, now_us: u64) -> u16 {
+        let target = self.level() as u16 * LEVEL_STEPS;
+        if target != self.ramp_to {
+            self.ramp_from = self.shown;
+            self.ramp_to = target;
+            self.ramp_start_us = now_us;
+        }
+
+        let ramp_us = (self.config.ramp_s.max(0.0) * 1_000_000.0) as u64;
+        let elapsed_us = now_us - self.ramp_start_us;
+        self.shown = if elapsed_us >= ramp_us {
+            target
+        } else {
+            let from = self.ramp_from as i64;
+            let change = (target as i64 - from) * elapsed_us as i64 / ramp_us as i64;
+            (from + change) as u16
+        };
+        self.shown
+    }
+
+    // Brightness level the output is heading for.
+    pub fn level(&self) -> u8 {
+        self.requested_level().min(self.max_level)
+    }
+
+    // Level from the mode, the sensor and the schedule.
+    fn requested_level(&self) -> u8 {
+        let offset = match self.mode {
+            Mode::Manual(level) => return level.min(MAX_LEVEL),
+            Mode::Auto => 0,
+            Mode::AutoOffset(offset) => offset,
+        };
+
+        let level = if self.fault {
+            self.config.fault_level.min(MAX_LEVEL)
+        } else {
+            let max_level = self.config.max_level.min(MAX_LEVEL);
+            let level = self
+                .curve_level
+                .clamp(self.config.min_level.min(max_level), max_level);
+            (level as i16 + offset as i16).clamp(0, MAX_LEVEL as i16) as u8
+        };
+
+        // A fixed level wins over caps, the first one in the schedule counts
+        let active = self.schedule.iter().flatten().filter(|entry| {
+            self.minute_of_day
+                .is_some_and(|minute| entry.is_active(minute))
+        });
+        let mut cap = MAX_LEVEL;
+        for entry in active {
+            match entry.action {
+                ScheduleAction::Fix(level) => return level.min(MAX_LEVEL),
+                ScheduleAction::Cap(level) => cap = cap.min(level),
+            }
+        }
+        level.min(cap)
+    }
+}
@@ -0,0 +1,53 @@
+// Path: src/clock_profile.rs
+//
+// System clock profiles, picked with the `clock-*` cargo features. Without one
+// the 302 MHz overclock is used, which not every board is stable at. If several
+// are enabled the slowest one wins, so `--all-features` builds still compile.
+//
+// The image looks the same with every profile. The bitplane on times are
+// counted in cycles, like the shifting of the lines around them, so the share
+// of the time the LEDs are on doesn't depend on the clock, only the refresh
+// rate does. The blanking times of `PanelConfig` are settling times of the
+// panel and are given in nanoseconds instead.
+use crate::bsp::hal::pll::PLLConfig;
+use fugit::HertzU32;
+
+#[cfg(feature = "clock-125mhz")]
+pub const PLL_SYS: PLLConfig = PLLConfig {
+    vco_freq: HertzU32::MHz(1500),
+    refdiv: 1,
+    post_div1: 6,
+    post_div2: 2,
+};
+
+#[cfg(all(feature = "clock-200mhz", not(feature = "clock-125mhz")))]
+pub const PLL_SYS: PLLConfig = PLLConfig {
+    vco_freq: HertzU32::MHz(1200),
+    refdiv: 1,
+    post_div1: 6,
+    post_div2: 1,
+};
+
+#[cfg(all(
+    feature = "clock-250mhz",
+    not(any(feature = "clock-125mhz", feature = "clock-200mhz"))
+))]
+pub const PLL_SYS: PLLConfig = PLLConfig {
+    vco_freq: HertzU32::MHz(1500),
+    refdiv: 1,
+    post_div1: 6,
+    post_div2: 1,
+};
+
+// 302.4 MHz, also without a clock feature.
+#[cfg(not(any(
+    feature = "clock-125mhz",
+    feature = "clock-200mhz",
+    feature = "clock-250mhz"
+)))]
+pub const PLL_SYS: PLLConfig = PLLConfig {
+    vco_freq: HertzU32::MHz(1512),
+    refdiv: 1,
+    post_div1: 5,
+    post_div2: 1,
+};
@@ -0,0 +1,109 @@
+// Path: src/flash.rs
+//
+// Writing to the flash the firmware runs from. Nothing may be fetched from
+// flash while it is erased or programmed, so the work is done by a function in
+// RAM with interrupts off, and core 1 waits in RAM until it is done.
+use crate::bsp::hal::rom_data;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+pub const SECTOR_SIZE: usize = 4096;
+pub const PAGE_SIZE: usize = 256;
+const XIP_BASE: usize = 0x1000_0000;
+const FLASH_SIZE: usize = 2048 * 1024;
+const BOOT2_WORDS: usize = 64;
+// 64 KiB block erase, used by the ROM where it fits the range.
+const BLOCK_SIZE: u32 = 1 << 16;
+const BLOCK_ERASE_CMD: u8 = 0xD8;
+
+// The last sector is left out of the firmware image in memory.x.
+pub const SETTINGS_OFFSET: u32 = (FLASH_SIZE - SECTOR_SIZE) as u32;
+
+static PARK_REQUEST: AtomicBool = AtomicBool::new(false);
+static PARKED: AtomicBool = AtomicBool::new(false);
+
+struct RomFunctions {
+    connect_internal_flash: unsafe extern "C" fn(),
+    flash_exit_xip: unsafe extern "C" fn(),
+    flash_range_erase: unsafe extern "C" fn(u32, usize, u32, u8),
+    flash_range_program: unsafe extern "C" fn(u32, *const u8, usize),
+    flash_flush_cache: unsafe extern "C" fn(),
+}
+
+// Flash contents at `offset`, read through the XIP window.
+pub fn read(offset: u32, len: usize) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts((XIP_BASE + offset as usize) as *const u8, len) }
+}
+
+// Has to be called regularly by core 1, which is parked in RAM here while core
+// 0 writes to the flash.
+pub fn poll_park() {
+    if PARK_REQUEST.load(Ordering::Acquire) {
+        park();
+    }
+}
+
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+fn park() {
+    PARKED.store(true, Ordering::Release);
+    while PARK_REQUEST.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+    PARKED.store(false, Ordering::Release);
+}
+
+// Erases the sectors starting at `offset` and programs `data` into them.
+// `offset` has to be sector aligned and `data` a whole number of pages. Core 1
+// has to be running and calling `poll_park`, otherwise this never returns.
+pub fn write(offset: u32, data: &[u8]) {
+    assert!((offset as usize).is_multiple_of(SECTOR_SIZE) && data.len().is_multiple_of(PAGE_SIZE));
+    let erase_len = data.len().div_ceil(SECTOR_SIZE) * SECTOR_SIZE;
+
+    // Look everything up while the flash can still be read. boot2 is copied
+    // so the fast XIP setup can be restored afterwards.
+    let rom = RomFunctions {
+        connect_internal_flash: rom_data::connect_internal_flash::ptr(),
+        flash_exit_xip: rom_data::flash_exit_xip::ptr(),
+        flash_range_erase: rom_data::flash_range_erase::ptr(),
+        flash_range_program: rom_data::flash_range_program::ptr(),
+        flash_flush_cache: rom_data::flash_flush_cache::ptr(),
+    };
+    let mut boot2 = [0u32; BOOT2_WORDS];
+    for (index, word) in boot2.iter_mut().enumerate() {
+        *word = unsafe { core::ptr::read_volatile((XIP_BASE as *const u32).add(index)) };
+    }
+
+    PARK_REQUEST.store(true, Ordering::Release);
+    while !PARKED.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+
+    cortex_m::interrupt::free(|_| unsafe {
+        erase_and_program(&rom, &boot2, offset, erase_len, data)
+    });
+
+    PARK_REQUEST.store(false, Ordering::Release);
+    while PARKED.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+}
+
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn erase_and_program(
+    rom: &RomFunctions,
+    boot2: &[u32; BOOT2_WORDS],
+    offset: u32,
+    erase_len: usize,
+    data: &[u8],
+) {
+    (rom.connect_internal_flash)();
+    (rom.flash_exit_xip)();
+    (rom.flash_range_erase)(offset, erase_len, BLOCK_SIZE, BLOCK_ERASE_CMD);
+    (rom.flash_range_program)(offset, data.as_ptr(), data.len());
+    (rom.flash_flush_cache)();
+
+    // Back to XIP through the copy of boot2, the Thumb bit makes it callable
+    let boot2: extern "C" fn() = core::mem::transmute((boot2.as_ptr() as *const u8).add(1));
+    boot2();
+}
@@ -0,0 +1,84 @@
+// Path: src/framebuffer.rs
+//
+// An RGB888 frame that embedded-graphics can draw on. Hand it to the matrix
+// with `swap_next_frame`.
+use crate::rgb_matrix::{Orientation, FRAME_BYTES};
+use core::convert::Infallible;
+use embedded_graphics::pixelcolor::{Rgb888, RgbColor};
+use embedded_graphics::prelude::{Dimensions, DrawTarget, OriginDimensions, Pixel, Size};
+use embedded_graphics::primitives::Rectangle;
+
+pub struct FrameBuffer {
+    pub(crate) data: [u8; FRAME_BYTES],
+    width: usize,
+    height: usize,
+}
+
+impl FrameBuffer {
+    // A black frame laid out for the given orientation of the matrix, so that
+    // (0, 0) ends up in the top left corner as seen by the viewer.
+    pub fn new(orientation: Orientation) -> FrameBuffer {
+        let (width, height) = orientation.frame_size();
+        FrameBuffer {
+            data: [0; FRAME_BYTES],
+            width,
+            height,
+        }
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize, color: Rgb888) {
+        let index = 3 * (y * self.width + x);
+        self.data[index..index + 3].copy_from_slice(&[color.r(), color.g(), color.b()]);
+    }
+}
+
+impl OriginDimensions for FrameBuffer {
+    fn size(&self) -> Size {
+        Size::new(self.width as u32, self.height as u32)
+    }
+}
+
+impl DrawTarget for FrameBuffer {
+    type Color = Rgb888;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            if point.x >= 0
+                && point.y >= 0
+                && (point.x as usize) < self.width
+                && (point.y as usize) < self.height
+            {
+                self.set_pixel(point.x as usize, point.y as usize, color);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Self::Color) -> Result<(), Self::Error> {
+        let area = area.intersection(&self.bounding_box());
+        let Some(bottom_right) = area.bottom_right() else {
+            return Ok(());
+        };
+
+        for y in area.top_left.y..=bottom_right.y {
+            for x in area.top_left.x..=bottom_right.x {
+                self.set_pixel(x as usize, y as usize, color);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+        for pixel in self.data.chunks_exact_mut(3) {
+            pixel.copy_from_slice(&[color.r(), color.g(), color.b()]);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,7 @@
+// Path: src/lib.rs
+//
+// The parts of the firmware that don't depend on the RP2040. They are built as
+// a library as well, so `cargo test --lib --target x86_64-unknown-linux-gnu`
+// (or your host's target) can run their tests on the host.
+#![cfg_attr(not(test), no_std)]
+pub mod lux_sensors;
@@ -0,0 +1,575 @@
+// Path: src/lux_sensors.rs
+//
+// Drivers for I2C ambient light sensors. They only use the embedded-hal I2C
+// traits, so they don't depend on the RP2040 and are tested on the host against
+// a mock bus, see src/lib.rs.
+//
+// All of them switch ranges on their own: when a reading is close to the top
+// of the range the sensor is made less sensitive, and when it is close to the
+// bottom more sensitive. Readings are skipped until a whole integration has
+// passed with the new setting. Only the sensor the board is configured for is
+// used.
+use embedded_hal::blocking::i2c::{Read, Write, WriteRead};
+use libm::powf;
+
+// Switch to a less sensitive range above this many counts, and to a more
+// sensitive one below `LOW_COUNTS`.
+const HIGH_COUNTS: u32 = 50_000;
+const LOW_COUNTS: u32 = 500;
+
+pub trait LuxSensor {
+    // The light level in lux, None while the sensor is settling or on errors.
+    // `now_us` is the current time, used to wait for integrations to finish.
+    fn read_lux(&mut self, now_us: u64) -> Option<f32>;
+}
+
+// Index of the range to use next, given the counts read in `range` of `ranges`
+// ranges sorted from most to least sensitive.
+fn next_range(range: usize, ranges: usize, counts: u32, high_counts: u32) -> usize {
+    if counts >= high_counts && range + 1 < ranges {
+        range + 1
+    } else if counts < LOW_COUNTS && range > 0 {
+        range - 1
+    } else {
+        range
+    }
+}
+
+// Vishay VEML7700.
+pub struct Veml7700<I2C> {
+    i2c: I2C,
+    range: usize,
+    configured: bool,
+    ready_at_us: u64,
+}
+
+const VEML7700_ADDRESS: u8 = 0x10;
+const VEML7700_ALS_CONF: u8 = 0x00;
+const VEML7700_ALS: u8 = 0x04;
+// (ALS_GAIN bits, gain, ALS_IT bits, integration time in ms)
+const VEML7700_RANGES: [(u16, f32, u16, u32); 5] = [
+    (0b01, 2.0, 0b0000, 100),
+    (0b00, 1.0, 0b0000, 100),
+    (0b11, 0.25, 0b0000, 100),
+    (0b10, 0.125, 0b0000, 100),
+    (0b10, 0.125, 0b1100, 25),
+];
+// Lux per count at gain 2 and 800 ms.
+const VEML7700_RESOLUTION: f32 = 0.0036;
+
+impl<I2C: Write + WriteRead> Veml7700<I2C> {
+    pub fn new(i2c: I2C) -> Veml7700<I2C> {
+        Veml7700 {
+            i2c,
+            range: 1,
+            configured: false,
+            ready_at_us: 0,
+        }
+    }
+
+    fn configure(&mut self, now_us: u64) -> Option<()> {
+        let (gain_bits, _, it_bits, it_ms) = VEML7700_RANGES[self.range];
+        let conf = gain_bits << 11 | it_bits << 6;
+        let [low, high] = conf.to_le_bytes();
+        self.i2c
+            .write(VEML7700_ADDRESS, &[VEML7700_ALS_CONF, low, high])
+            .ok()?;
+
+        // The first result after a change can still be from the old setting
+        self.ready_at_us = now_us + 2 * it_ms as u64 * 1000;
+        self.configured = true;
+        Some(())
+    }
+}
+
+impl<I2C: Write + WriteRead> LuxSensor for Veml7700<I2C> {
+    fn read_lux(&mut self, now_us: u64) -> Option<f32> {
+        if !self.configured {
+            self.configure(now_us)?;
+        }
+        if now_us < self.ready_at_us {
+            return None;
+        }
+
+        let mut data = [0; 2];
+        if self
+            .i2c
+            .write_read(VEML7700_ADDRESS, &[VEML7700_ALS], &mut data)
+            .is_err()
+        {
+            self.configured = false;
+            return None;
+        }
+        let counts = u16::from_le_bytes(data) as u32;
+        let saturated = counts == u16::MAX as u32;
+
+        let (_, gain, _, it_ms) = VEML7700_RANGES[self.range];
+        let resolution = VEML7700_RESOLUTION * (800.0 / it_ms as f32) * (2.0 / gain);
+        let lux = counts as f32 * resolution;
+        // Correction for the non-linearity at high light levels, from the
+        // Vishay application note.
+        let lux = 6.0135e-13 * powf(lux, 4.0) - 9.3924e-9 * powf(lux, 3.0)
+            + 8.1488e-5 * powf(lux, 2.0)
+            + 1.0023 * lux;
+
+        let range = next_range(self.range, VEML7700_RANGES.len(), counts, HIGH_COUNTS);
+        if range != self.range {
+            self.range = range;
+            self.configure(now_us)?;
+        }
+
+        (!saturated).then_some(lux)
+    }
+}
+
+// Rohm BH1750.
+pub struct Bh1750<I2C> {
+    i2c: I2C,
+    range: usize,
+    configured: bool,
+    ready_at_us: u64,
+}
+
+const BH1750_ADDRESS: u8 = 0x23;
+const BH1750_POWER_ON: u8 = 0x01;
+const BH1750_CONTINUOUS_HIGH_RES: u8 = 0x10;
+const BH1750_DEFAULT_MTREG: u32 = 69;
+// Measurement time register values, the default is 69.
+const BH1750_RANGES: [u8; 3] = [254, 69, 31];
+
+impl<I2C: Write + Read> Bh1750<I2C> {
+    pub fn new(i2c: I2C) -> Bh1750<I2C> {
+        Bh1750 {
+            i2c,
+            range: 1,
+            configured: false,
+            ready_at_us: 0,
+        }
+    }
+
+    fn configure(&mut self, now_us: u64) -> Option<()> {
+        let mtreg = BH1750_RANGES[self.range];
+        for command in [
+            BH1750_POWER_ON,
+            0x40 | mtreg >> 5,
+            0x60 | (mtreg & 0x1F),
+            BH1750_CONTINUOUS_HIGH_RES,
+        ] {
+            self.i2c.write(BH1750_ADDRESS, &[command]).ok()?;
+        }
+
+        // 120 ms at the default measurement time, twice to be sure
+        let measurement_us = 120_000 * mtreg as u64 / BH1750_DEFAULT_MTREG as u64;
+        self.ready_at_us = now_us + 2 * measurement_us;
+        self.configured = true;
+        Some(())
+    }
+}
+
+impl<I2C: Write + Read> LuxSensor for Bh1750<I2C> {
+    fn read_lux(&mut self, now_us: u64) -> Option<f32> {
+        if !self.configured {
+            self.configure(now_us)?;
+        }
+        if now_us < self.ready_at_us {
+            return None;
+        }
+
+        let mut data = [0; 2];
+        if self.i2c.read(BH1750_ADDRESS, &mut data).is_err() {
+            self.configured = false;
+            return None;
+        }
+        let counts = u16::from_be_bytes(data) as u32;
+        let saturated = counts == u16::MAX as u32;
+
+        let mtreg = BH1750_RANGES[self.range] as f32;
+        let lux = counts as f32 / 1.2 * (BH1750_DEFAULT_MTREG as f32 / mtreg);
+
+        let range = next_range(self.range, BH1750_RANGES.len(), counts, HIGH_COUNTS);
+        if range != self.range {
+            self.range = range;
+            self.configure(now_us)?;
+        }
+
+        (!saturated).then_some(lux)
+    }
+}
+
+// ams TSL2591.
+pub struct Tsl2591<I2C> {
+    i2c: I2C,
+    range: usize,
+    configured: bool,
+    ready_at_us: u64,
+}
+
+const TSL2591_ADDRESS: u8 = 0x29;
+// Command bit plus normal operation, or'ed with the register.
+const TSL2591_COMMAND: u8 = 0xA0;
+const TSL2591_ENABLE: u8 = 0x00;
+const TSL2591_CONTROL: u8 = 0x01;
+const TSL2591_C0DATAL: u8 = 0x14;
+const TSL2591_POWER_ON_ALS: u8 = 0x03;
+const TSL2591_INTEGRATION_MS: u32 = 100;
+// Full scale at 100 ms integration.
+const TSL2591_MAX_COUNTS: u32 = 36_863;
+// (AGAIN bits, gain)
+const TSL2591_RANGES: [(u8, f32); 4] = [(0b11, 9876.0), (0b10, 428.0), (0b01, 25.0), (0b00, 1.0)];
+// Device factor from the ams lux equation.
+const TSL2591_LUX_DF: f32 = 408.0;
+
+impl<I2C: Write + WriteRead> Tsl2591<I2C> {
+    pub fn new(i2c: I2C) -> Tsl2591<I2C> {
+        Tsl2591 {
+            i2c,
+            range: 2,
+            configured: false,
+            ready_at_us: 0,
+        }
+    }
+
+    fn configure(&mut self, now_us: u64) -> Option<()> {
+        let (gain_bits, _) = TSL2591_RANGES[self.range];
+        // ATIME 0 is 100 ms
+        self.i2c
+            .write(
+                TSL2591_ADDRESS,
+                &[TSL2591_COMMAND | TSL2591_CONTROL, gain_bits << 4],
+            )
+            .ok()?;
+        self.i2c
+            .write(
+                TSL2591_ADDRESS,
+                &[TSL2591_COMMAND | TSL2591_ENABLE, TSL2591_POWER_ON_ALS],
+            )
+            .ok()?;
+
+        self.ready_at_us = now_us + 2 * TSL2591_INTEGRATION_MS as u64 * 1000;
+        self.configured = true;
+        Some(())
+    }
+}
+
+impl<I2C: Write + WriteRead> LuxSensor for Tsl2591<I2C> {
+    fn read_lux(&mut self, now_us: u64) -> Option<f32> {
+        if !self.configured {
+            self.configure(now_us)?;
+        }
+        if now_us < self.ready_at_us {
+            return None;
+        }
+
+        let mut data = [0; 4];
+        if self
+            .i2c
+            .write_read(
+                TSL2591_ADDRESS,
+                &[TSL2591_COMMAND | TSL2591_C0DATAL],
+                &mut data,
+            )
+            .is_err()
+        {
+            self.configured = false;
+            return None;
+        }
+        // Channel 0 is visible plus infrared, channel 1 infrared only
+        let full = u16::from_le_bytes([data[0], data[1]]) as u32;
+        let infrared = u16::from_le_bytes([data[2], data[3]]) as u32;
+
+        let high_counts = TSL2591_MAX_COUNTS * 9 / 10;
+        let range = next_range(self.range, TSL2591_RANGES.len(), full, high_counts);
+        let (_, gain) = TSL2591_RANGES[self.range];
+        let saturated = full >= TSL2591_MAX_COUNTS;
+        if range != self.range {
+            self.range = range;
+            self.configure(now_us)?;
+        }
+        if saturated {
+            return None;
+        }
+
+        let counts_per_lux = TSL2591_INTEGRATION_MS as f32 * gain / TSL2591_LUX_DF;
+        let lux = if full == 0 {
+            0.0
+        } else {
+            let full = full as f32;
+            let infrared = infrared as f32;
+            (full - infrared) * (1.0 - infrared / full) / counts_per_lux
+        };
+
+        Some(lux.max(0.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_hal_mock::eh0::i2c::{Mock, Transaction};
+    use embedded_hal_mock::eh0::MockError;
+    use std::io::ErrorKind;
+
+    fn assert_lux(lux: Option<f32>, expected: f32) {
+        let lux = lux.expect("no reading");
+        assert!(
+            (lux - expected).abs() <= expected * 0.001,
+            "{} lux, expected {}",
+            lux,
+            expected
+        );
+    }
+
+    fn i2c_error() -> MockError {
+        MockError::Io(ErrorKind::Other)
+    }
+
+    fn veml7700_read(counts: u16) -> Transaction {
+        let data = counts.to_le_bytes().to_vec();
+        Transaction::write_read(VEML7700_ADDRESS, vec![VEML7700_ALS], data)
+    }
+
+    fn veml7700_configure(conf: u16) -> Transaction {
+        let [low, high] = conf.to_le_bytes();
+        Transaction::write(VEML7700_ADDRESS, vec![VEML7700_ALS_CONF, low, high])
+    }
+
+    #[test]
+    fn veml7700_converts_counts_to_lux() {
+        let mut i2c = Mock::new(&[veml7700_configure(0x0000), veml7700_read(1000)]);
+        let mut sensor = Veml7700::new(i2c.clone());
+
+        // Gain 1 and 100 ms, waits for two integrations
+        assert_eq!(sensor.read_lux(0), None);
+        assert_eq!(sensor.read_lux(199_999), None);
+        assert_lux(sensor.read_lux(200_000), 58.001);
+        i2c.done();
+    }
+
+    #[test]
+    fn veml7700_switches_ranges() {
+        let mut i2c = Mock::new(&[
+            veml7700_configure(0x0000),
+            // Gain 1/4 when close to the top
+            veml7700_read(50_000),
+            veml7700_configure(0x1800),
+            // Back to gain 1, then gain 2 when close to the bottom
+            veml7700_read(400),
+            veml7700_configure(0x0000),
+            veml7700_read(400),
+            veml7700_configure(0x0800),
+        ]);
+        let mut sensor = Veml7700::new(i2c.clone());
+
+        sensor.read_lux(0);
+        assert_lux(sensor.read_lux(200_000), 3379.52);
+        assert_eq!(sensor.read_lux(300_000), None);
+        assert_lux(sensor.read_lux(400_000), 93.057);
+        assert_lux(sensor.read_lux(600_000), 23.1361);
+        assert_eq!(sensor.range, 0);
+        i2c.done();
+    }
+
+    #[test]
+    fn veml7700_skips_saturated_readings() {
+        let mut i2c = Mock::new(&[
+            veml7700_configure(0x0000),
+            veml7700_read(u16::MAX),
+            veml7700_configure(0x1800),
+        ]);
+        let mut sensor = Veml7700::new(i2c.clone());
+
+        sensor.read_lux(0);
+        assert_eq!(sensor.read_lux(200_000), None);
+        assert_eq!(sensor.range, 2);
+        i2c.done();
+    }
+
+    #[test]
+    fn veml7700_reconfigures_after_errors() {
+        let mut i2c = Mock::new(&[
+            veml7700_configure(0x0000),
+            veml7700_read(1000).with_error(i2c_error()),
+            veml7700_configure(0x0000),
+            veml7700_read(1000),
+        ]);
+        let mut sensor = Veml7700::new(i2c.clone());
+
+        sensor.read_lux(0);
+        assert_eq!(sensor.read_lux(200_000), None);
+        assert_eq!(sensor.read_lux(300_000), None);
+        assert_lux(sensor.read_lux(500_000), 58.001);
+        i2c.done();
+    }
+
+    fn bh1750_configure(mtreg: u8) -> [Transaction; 4] {
+        [
+            BH1750_POWER_ON,
+            0x40 | mtreg >> 5,
+            0x60 | (mtreg & 0x1F),
+            BH1750_CONTINUOUS_HIGH_RES,
+        ]
+        .map(|command| Transaction::write(BH1750_ADDRESS, vec![command]))
+    }
+
+    fn bh1750_read(counts: u16) -> Transaction {
+        Transaction::read(BH1750_ADDRESS, counts.to_be_bytes().to_vec())
+    }
+
+    #[test]
+    fn bh1750_converts_counts_to_lux() {
+        let mut expectations = bh1750_configure(69).to_vec();
+        expectations.push(bh1750_read(1200));
+        let mut i2c = Mock::new(&expectations);
+        let mut sensor = Bh1750::new(i2c.clone());
+
+        // 120 ms at the default measurement time, waits for two
+        assert_eq!(sensor.read_lux(0), None);
+        assert_eq!(sensor.read_lux(239_999), None);
+        assert_lux(sensor.read_lux(240_000), 1000.0);
+        i2c.done();
+    }
+
+    #[test]
+    fn bh1750_switches_ranges() {
+        let mut expectations = bh1750_configure(69).to_vec();
+        expectations.push(bh1750_read(60_000));
+        expectations.extend(bh1750_configure(31));
+        expectations.push(bh1750_read(400));
+        expectations.extend(bh1750_configure(69));
+        expectations.push(bh1750_read(300));
+        expectations.extend(bh1750_configure(254));
+        let mut i2c = Mock::new(&expectations);
+        let mut sensor = Bh1750::new(i2c.clone());
+
+        sensor.read_lux(0);
+        assert_lux(sensor.read_lux(240_000), 50_000.0);
+        assert_lux(sensor.read_lux(500_000), 400.0 / 1.2 * 69.0 / 31.0);
+        assert_lux(sensor.read_lux(800_000), 250.0);
+        assert_eq!(sensor.range, 0);
+        // The longer measurement time also takes longer to settle
+        assert_eq!(sensor.read_lux(800_000 + 2 * 120_000), None);
+        i2c.done();
+    }
+
+    #[test]
+    fn bh1750_skips_saturated_readings() {
+        let mut expectations = bh1750_configure(69).to_vec();
+        expectations.push(bh1750_read(u16::MAX));
+        expectations.extend(bh1750_configure(31));
+        let mut i2c = Mock::new(&expectations);
+        let mut sensor = Bh1750::new(i2c.clone());
+
+        sensor.read_lux(0);
+        assert_eq!(sensor.read_lux(240_000), None);
+        assert_eq!(sensor.range, 2);
+        i2c.done();
+    }
+
+    #[test]
+    fn bh1750_reconfigures_after_errors() {
+        let mut expectations = bh1750_configure(69).to_vec();
+        expectations.push(bh1750_read(1200).with_error(i2c_error()));
+        expectations.extend(bh1750_configure(69));
+        expectations.push(bh1750_read(1200));
+        let mut i2c = Mock::new(&expectations);
+        let mut sensor = Bh1750::new(i2c.clone());
+
+        sensor.read_lux(0);
+        assert_eq!(sensor.read_lux(240_000), None);
+        assert_eq!(sensor.read_lux(300_000), None);
+        assert_lux(sensor.read_lux(540_000), 1000.0);
+        i2c.done();
+    }
+
+    fn tsl2591_configure(gain_bits: u8) -> [Transaction; 2] {
+        [
+            Transaction::write(
+                TSL2591_ADDRESS,
+                vec![TSL2591_COMMAND | TSL2591_CONTROL, gain_bits << 4],
+            ),
+            Transaction::write(
+                TSL2591_ADDRESS,
+                vec![TSL2591_COMMAND | TSL2591_ENABLE, TSL2591_POWER_ON_ALS],
+            ),
+        ]
+    }
+
+    fn tsl2591_read(full: u16, infrared: u16) -> Transaction {
+        let [full_low, full_high] = full.to_le_bytes();
+        let [infrared_low, infrared_high] = infrared.to_le_bytes();
+        Transaction::write_read(
+            TSL2591_ADDRESS,
+            vec![TSL2591_COMMAND | TSL2591_C0DATAL],
+            vec![full_low, full_high, infrared_low, infrared_high],
+        )
+    }
+
+    #[test]
+    fn tsl2591_converts_counts_to_lux() {
+        let mut expectations = tsl2591_configure(0b01).to_vec();
+        expectations.push(tsl2591_read(1000, 200));
+        // Dark, so it also switches to gain 428
+        expectations.push(tsl2591_read(0, 0));
+        expectations.extend(tsl2591_configure(0b10));
+        let mut i2c = Mock::new(&expectations);
+        let mut sensor = Tsl2591::new(i2c.clone());
+
+        // Gain 25 and 100 ms, waits for two integrations
+        assert_eq!(sensor.read_lux(0), None);
+        assert_eq!(sensor.read_lux(199_999), None);
+        assert_lux(sensor.read_lux(200_000), 104.448);
+        assert_eq!(sensor.read_lux(300_000), Some(0.0));
+        i2c.done();
+    }
+
+    #[test]
+    fn tsl2591_switches_ranges() {
+        let mut expectations = tsl2591_configure(0b01).to_vec();
+        expectations.push(tsl2591_read(34_000, 2000));
+        expectations.extend(tsl2591_configure(0b00));
+        expectations.push(tsl2591_read(400, 100));
+        expectations.extend(tsl2591_configure(0b01));
+        expectations.push(tsl2591_read(400, 100));
+        expectations.extend(tsl2591_configure(0b10));
+        let mut i2c = Mock::new(&expectations);
+        let mut sensor = Tsl2591::new(i2c.clone());
+
+        // The reading that switches is still converted with the old gain
+        sensor.read_lux(0);
+        assert_lux(sensor.read_lux(200_000), 4915.2);
+        assert_lux(sensor.read_lux(400_000), 25.0 * 36.72);
+        assert_lux(sensor.read_lux(600_000), 36.72);
+        assert_eq!(sensor.range, 1);
+        i2c.done();
+    }
+
+    #[test]
+    fn tsl2591_skips_saturated_readings() {
+        let mut expectations = tsl2591_configure(0b01).to_vec();
+        expectations.push(tsl2591_read(TSL2591_MAX_COUNTS as u16, 2000));
+        expectations.extend(tsl2591_configure(0b00));
+        let mut i2c = Mock::new(&expectations);
+        let mut sensor = Tsl2591::new(i2c.clone());
+
+        sensor.read_lux(0);
+        assert_eq!(sensor.read_lux(200_000), None);
+        assert_eq!(sensor.range, 3);
+        i2c.done();
+    }
+
+    #[test]
+    fn tsl2591_reconfigures_after_errors() {
+        let mut expectations = tsl2591_configure(0b01).to_vec();
+        expectations.push(tsl2591_read(1000, 200).with_error(i2c_error()));
+        expectations.extend(tsl2591_configure(0b01));
+        expectations.push(tsl2591_read(1000, 200));
+        let mut i2c = Mock::new(&expectations);
+        let mut sensor = Tsl2591::new(i2c.clone());
+
+        sensor.read_lux(0);
+        assert_eq!(sensor.read_lux(200_000), None);
+        assert_eq!(sensor.read_lux(300_000), None);
+        assert_lux(sensor.read_lux(500_000), 104.448);
+        i2c.done();
+    }
+}
@@ -1,25 +1,100 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 
+#[cfg(not(test))]
 use bsp::entry;
 use bsp::hal;
+use bsp::hal::clocks::{Clock, StoppableClock};
 use bsp::hal::pac;
-use bsp::hal::{clocks::StoppableClock, gpio::FunctionSpi};
+use core::ptr::{addr_of, addr_of_mut};
 use defmt::*;
+#[cfg(not(test))]
 use defmt_rtt as _;
-use embedded_hal::adc::OneShot;
+use embedded_hal::digital::v2::{InputPin, OutputPin};
 use embedded_hal::spi::FullDuplex;
 use fugit::RateExtU32;
+#[cfg(not(test))]
 use panic_probe as _;
 use rp_pico as bsp;
+mod ambient;
+mod board;
+mod brightness;
+mod clock_profile;
+mod flash;
+mod framebuffer;
+mod overlay;
+mod protocol;
 mod rgb_matrix;
+mod settings;
+mod supply;
+mod test_patterns;
+mod thermal;
 
 static mut CORE1_STACK: hal::multicore::Stack<4096> = hal::multicore::Stack::new();
-static mut LED_FRAME: [u8; 96 * 48 * 3] = [0u8; 96 * 48 * 3];
-static mut CURRENT_POSITION: usize = 0;
-static BRIGHTNESS_EXP_ALPHA: f32 = 0.995;
+// Core 1 receives messages into one buffer while core 0 handles the other.
+static mut INPUT_BUFFERS: [[u8; protocol::MAX_PAYLOAD]; 2] = [[0u8; protocol::MAX_PAYLOAD]; 2];
+// Quirks of the panel that is connected: colour order, inverted control lines
+// and the blanking times around the row switch.
+static PANEL_CONFIG: rgb_matrix::PanelConfig = rgb_matrix::PanelConfig {
+    channel_order: rgb_matrix::ChannelOrder::Rgb,
+    invert_data: false,
+    invert_output_enable: false,
+    invert_latch: false,
+    blank_before_address_ns: 0,
+    blank_after_latch_ns: 0,
+    precharge_black_line: false,
+};
+// Rotation and mirroring of the frames on the panel. Rotating by 90 or 270
+// degrees makes the frames 48x96.
+static ORIENTATION: rgb_matrix::Orientation = rgb_matrix::Orientation {
+    rotation: rgb_matrix::Rotation::Deg0,
+    mirror_horizontal: false,
+    mirror_vertical: false,
+};
+// Bitplanes per refresh, 11 for full colour depth or fewer for a faster refresh.
+static PWM_BITS: u8 = 11;
+// Split the long bitplanes into this many interleaved pieces to reduce flicker, 1 to disable.
+static PLANE_SPLITS: u8 = 1;
+// Spread fractional pixel values over several refreshes for smoother dim gradients.
+static TEMPORAL_DITHERING: bool = false;
+// Lock the refresh rate to a fixed value for filming, None to refresh as fast as possible.
+static REFRESH_LIMIT_HZ: Option<u32> = None;
+// Draw the measured refresh rate in the corner of the panel.
+static SHOW_REFRESH_RATE: bool = false;
+// Lower the brightness when the estimated panel current would exceed the budget,
+// None to disable. The LED currents are for a single LED of each colour.
+static POWER_LIMIT: Option<rgb_matrix::PowerLimit> = None;
+// Lower the brightness when the chip gets hot. None to disable. `slow_clock_c`
+// also halves the system clock, e.g. Some(80.0), which halves the fastest SPI
+// input clock too.
+static THERMAL_LIMIT: Option<thermal::ThermalLimit> = Some(thermal::ThermalLimit {
+    derate_c: 70.0,
+    step_c: 5.0,
+    slow_clock_c: None,
+    hysteresis_c: 3.0,
+});
+// Lower the brightness when the supply voltage (VSYS) drops, and blank the panel
+// below a critical voltage, for battery powered units. None to disable. Only
+// boards that measure VSYS can use it.
+static SUPPLY_LIMIT: Option<supply::SupplyLimit> = None;
+// Stop scanning once the frame has been all black for this long, e.g.
+// Some(2_000_000), None to keep scanning. Any message from the host wakes the
+// panel again.
+static IDLE_BLACK_US: Option<u64> = None;
+// Also stop scanning when no message arrived for this long, None to keep
+// showing the last frame.
+static IDLE_INPUT_TIMEOUT_US: Option<u64> = None;
+// Switch the panel supply off with the board's panel power pin while idle.
+static IDLE_PANEL_POWER_OFF: bool = false;
+// Divide the system clock by this while idle, 1 to leave it alone. The SPI
+// input can only be clocked at up to a 12th of the system clock, so a host
+// clocking faster than that loses the message that should wake the panel.
+static IDLE_CLOCK_DIVIDER: u32 = 1;
+static REFRESH_LOG_INTERVAL_US: u64 = 1_000_000;
+// Time between frames of the animated test patterns.
+static TEST_PATTERN_STEP_US: u64 = 100_000;
 
-#[entry]
+#[cfg_attr(not(test), entry)]
 fn main() -> ! {
     let mut pac = pac::Peripherals::take().unwrap();
     let _core = pac::CorePeripherals::take().unwrap();
@@ -27,7 +102,7 @@ fn main() -> ! {
     let mut sio = hal::sio::Sio::new(pac.SIO);
 
     // Step 1. Set up clocks. We're doing this manually here, because we're overclocking it.
-    // This is to reduce the flicker on the matrix.
+    // This is to reduce the flicker on the matrix. The speed comes from the clock profile.
 
     // Set up the system clock to use the external oscillator, running at 12 MHz.
     let xosc = hal::xosc::setup_xosc_blocking(pac.XOSC, bsp::XOSC_CRYSTAL_FREQ.Hz())
@@ -42,12 +117,7 @@ fn main() -> ! {
     let pll_sys = hal::pll::setup_pll_blocking(
         pac.PLL_SYS,
         xosc.operating_frequency(),
-        hal::pll::PLLConfig {
-            vco_freq: 1512.MHz(),
-            refdiv: 1,
-            post_div1: 5,
-            post_div2: 1,
-        },
+        clock_profile::PLL_SYS,
         &mut clocks,
         &mut pac.RESETS,
     )
@@ -75,7 +145,25 @@ fn main() -> ! {
     clocks.gpio_output1_clock.disable();
     clocks.gpio_output2_clock.disable();
     clocks.gpio_output3_clock.disable();
-    clocks.rtc_clock.disable();
+
+    // The RTC keeps the time of day for the brightness schedule. It doesn't
+    // survive a reset, so the schedule waits until the host has set the time.
+    let mut rtc = hal::rtc::RealTimeClock::new(
+        pac.RTC,
+        clocks.rtc_clock,
+        &mut pac.RESETS,
+        hal::rtc::DateTime {
+            year: 2000,
+            month: 1,
+            day: 1,
+            day_of_week: hal::rtc::DayOfWeek::Saturday,
+            hour: 0,
+            minute: 0,
+            second: 0,
+        },
+    )
+    .unwrap();
+    let mut time_set = false;
 
     // Set up the peripherals.
     let pins = bsp::Pins::new(
@@ -85,101 +173,421 @@ fn main() -> ! {
         &mut pac.RESETS,
     );
 
-    // Set up the RGB matrix.
-    let rgb_r0 = pins.gpio0.into_push_pull_output();
-    let rgb_g0 = pins.gpio1.into_push_pull_output();
-    let rgb_b0 = pins.gpio2.into_push_pull_output();
-    let rgb_r1 = pins.gpio3.into_push_pull_output();
-    let rgb_g1 = pins.gpio4.into_push_pull_output();
-    let rgb_b1 = pins.gpio5.into_push_pull_output();
-
-    let addr_a = pins.gpio6.into_push_pull_output();
-    let addr_b = pins.gpio7.into_push_pull_output();
-    let addr_c = pins.gpio8.into_push_pull_output();
-    let addr_d = pins.gpio9.into_push_pull_output();
-    let addr_e = pins.gpio10.into_push_pull_output();
-
-    let clock = pins.gpio11.into_push_pull_output();
-    let latch = pins.gpio12.into_push_pull_output();
-    let output_enable = pins.gpio13.into_push_pull_output();
-
-    let rgb_pins = rgb_matrix::RgbPins::new(rgb_r0, rgb_g0, rgb_b0, rgb_r1, rgb_g1, rgb_b1);
-    let addr_pins = rgb_matrix::AddrPins::new(addr_a, addr_b, addr_c, addr_d, addr_e);
-    let latch = rgb_matrix::LatchPin::new(latch);
-    let clock = rgb_matrix::ClockPin::new(clock);
-    let output_enable = rgb_matrix::OutputEnablePin::new(output_enable);
-
-    let mut matrix =
-        rgb_matrix::RgbMatrix96x48::new(rgb_pins, addr_pins, latch, clock, output_enable);
-
-    // Set up the second core to read the SPI data and write it to the buffer.
+    // The timer counts the 1 us watchdog ticks, it is used to measure the refresh rate.
+    // The first alarm drives the light sensor sampling.
+    let timer =
+        cortex_m::singleton!(: hal::Timer = hal::Timer::new(pac.TIMER, &mut pac.RESETS)).unwrap();
+    let ambient_alarm = timer.alarm_0().unwrap();
+    let timer: &'static hal::Timer = timer;
+
+    // Set up the RGB matrix, using the pin mapping of the selected board.
+    let board = board::split(pins);
+
+    let mut matrix = rgb_matrix::RgbMatrix96x48::new(
+        board.rgb_pins,
+        board::row_address(board.addr_pins),
+        board.latch,
+        board.clock,
+        board.output_enable,
+        PANEL_CONFIG,
+        timer,
+    );
+    matrix.set_system_clock(clocks.system_clock.freq());
+    matrix.set_orientation(ORIENTATION);
+    matrix.set_pwm_bits(PWM_BITS);
+    matrix.set_plane_splits(PLANE_SPLITS);
+    matrix.set_dithering(TEMPORAL_DITHERING);
+    matrix.set_refresh_limit(REFRESH_LIMIT_HZ);
+    matrix.set_show_refresh(SHOW_REFRESH_RATE);
+    matrix.set_power_limit(POWER_LIMIT);
+    let mut panel_power = board.panel_power;
+    panel_power.set_high().unwrap();
+
+    // Set up the second core to read the SPI messages into the input buffers.
+    // Finished messages are passed to core 0 through the FIFO, which hands the
+    // buffer index back once it is done with it.
+    let input_spi = board::input_spi_device(pac.SPI0, pac.SPI1);
     let mut mc = hal::multicore::Multicore::new(&mut pac.PSM, &mut pac.PPB, &mut sio.fifo);
     let cores = mc.cores();
     let core1 = &mut cores[1];
     core1
-        .spawn(unsafe { &mut CORE1_STACK.mem }, move || {
+        .spawn(unsafe { &mut *addr_of_mut!(CORE1_STACK.mem) }, move || {
             let mut pac = unsafe { pac::Peripherals::steal() };
+            let mut sio = hal::Sio::new(pac.SIO);
 
             // Set up the SPI driver
-            let _spi_mosi = pins.gpio19.into_mode::<FunctionSpi>();
-            let _spi_miso = pins.gpio16.into_mode::<FunctionSpi>();
-            let _spi_sck = pins.gpio18.into_mode::<FunctionSpi>();
-            let spi = hal::spi::Spi::<_, _, 8>::new(pac.SPI0);
+            let _input_pins = board.input_pins;
+            let spi = hal::spi::Spi::<_, _, 8>::new(input_spi);
 
             let mut spi = spi.init_slave(&mut pac.RESETS, &embedded_hal::spi::MODE_3);
 
+            let mut parser = protocol::Parser::new();
+            let mut busy = [false; 2];
+            let mut buffer = 0;
             loop {
+                flash::poll_park();
+
+                while let Some(released) = sio.fifo.read() {
+                    busy[released as usize] = false;
+                }
+
                 if let Ok(value) = spi.read() {
-                    unsafe {
-                        LED_FRAME[CURRENT_POSITION] = value;
-                        CURRENT_POSITION += 1;
-                        if CURRENT_POSITION >= 96 * 48 * 3 {
-                            CURRENT_POSITION = 0;
-                        }
+                    // Messages arriving while core 0 still holds both buffers are dropped.
+                    let input: &mut [u8] = if busy[buffer] {
+                        &mut []
+                    } else {
+                        unsafe { &mut *addr_of_mut!(INPUT_BUFFERS[buffer]) }
+                    };
+
+                    if let Some((command, length)) = parser.push(value, input) {
+                        busy[buffer] = true;
+                        let message = protocol::Message {
+                            command,
+                            buffer,
+                            length,
+                        };
+                        sio.fifo.write(message.to_word());
+                        buffer ^= 1;
                     }
                 }
             }
         })
         .unwrap();
 
-    // Set up the ADC and the brightness sensor.
-    let mut adc = hal::Adc::new(pac.ADC, &mut pac.RESETS);
-    let mut brightness_sensor_adc = pins.gpio28.into_floating_input();
+    // Sample the brightness sensor in the background.
+    let adc = hal::Adc::new(pac.ADC, &mut pac.RESETS);
+    let light_sensor = board::light_sensor(
+        board.analog_light_sensor,
+        board.i2c_pins,
+        board::i2c_device(pac.I2C0, pac.I2C1),
+        &mut pac.RESETS,
+        clocks.system_clock.freq(),
+    );
+    ambient::start(
+        adc,
+        light_sensor,
+        board.supply_sensor,
+        ambient_alarm,
+        timer.get_counter(),
+    );
+
+    // Holding the test button at boot shows the test patterns until the host
+    // sends a frame. Each press moves on to the next pattern.
+    let test_button = board.test_button;
+    let mut test_pattern = if test_button.is_low().unwrap() {
+        Some(test_patterns::TestPattern::ColorBars)
+    } else {
+        None
+    };
+    let mut test_frame = framebuffer::FrameBuffer::new(matrix.orientation());
+    let mut test_step = 0;
+    let mut test_button_was_low = true;
+    let mut last_test_step = timer.get_counter();
 
-    // Keep track of the brightness with an exponential moving average
-    let mut brightness: f32 = 1600.0;
+    // The brightness follows the ambient light along the stored curve.
+    let mut settings = settings::Settings::load();
+    let mut brightness = brightness::BrightnessController::new(
+        settings.brightness,
+        settings.mode,
+        settings.schedule,
+    );
+    ambient::configure(&settings.brightness);
+    let mut sensor_fault = false;
+    let mut thermal_guard = THERMAL_LIMIT.map(thermal::ThermalGuard::new);
+    let mut clock_slowed = false;
+    let mut supply_guard = SUPPLY_LIMIT.map(supply::SupplyGuard::new);
+    let mut supply_max_level = brightness::MAX_LEVEL;
+    let mut supply_blank = false;
+    let mut clock_divider = 1;
+    let mut idle = false;
+    let mut last_input = timer.get_counter();
+    let mut last_lit = last_input;
+    let mut last_refresh_log = timer.get_counter();
     loop {
-        unsafe {
-            matrix.set_next_frame(&LED_FRAME);
+        while let Some(word) = sio.fifo.read() {
+            let Some(message) = protocol::Message::from_word(word) else {
+                continue;
+            };
+            let buffer = unsafe { &*addr_of!(INPUT_BUFFERS[message.buffer]) };
+            let payload = &buffer[..message.length];
+            // Counts as lit as well, so a new frame is shown before going idle
+            last_input = timer.get_counter();
+            last_lit = last_input;
+
+            if matches!(
+                message.command,
+                protocol::Command::Frame | protocol::Command::Rect
+            ) {
+                test_pattern = None;
+            }
+
+            let result = match message.command {
+                protocol::Command::Frame => match payload.split_first() {
+                    Some((&code, pixels)) => protocol::pixel_format(code)
+                        .ok_or(rgb_matrix::Error)
+                        .and_then(|format| matrix.set_next_frame_format(pixels, format)),
+                    None => Err(rgb_matrix::Error),
+                },
+                protocol::Command::Palette => matrix.set_palette(payload),
+                protocol::Command::Rect => match payload {
+                    [code, x, y, width, height, pixels @ ..] => protocol::pixel_format(*code)
+                        .ok_or(rgb_matrix::Error)
+                        .and_then(|format| {
+                            matrix.update_rect(
+                                *x as usize,
+                                *y as usize,
+                                *width as usize,
+                                *height as usize,
+                                pixels,
+                                format,
+                            )
+                        }),
+                    _ => Err(rgb_matrix::Error),
+                },
+                protocol::Command::TestPattern => match payload {
+                    [protocol::TEST_PATTERN_OFF] => {
+                        test_pattern = None;
+                        Ok(())
+                    }
+                    [code] => test_patterns::TestPattern::from_u8(*code)
+                        .map(|pattern| {
+                            test_pattern = Some(pattern);
+                            test_step = 0;
+                        })
+                        .ok_or(rgb_matrix::Error),
+                    _ => Err(rgb_matrix::Error),
+                },
+                protocol::Command::BrightnessConfig => {
+                    settings::decode_brightness_message(&mut settings::Reader::new(payload))
+                        .map(|config| {
+                            brightness.set_config(config);
+                            ambient::configure(&config);
+                            settings.brightness = config;
+                            settings.save();
+                        })
+                        .ok_or(rgb_matrix::Error)
+                }
+                protocol::Command::Calibrate => {
+                    // Only the analog sensor needs it, and only a good reading will do
+                    let raw = ambient::ambient().filter(|_| {
+                        !board::LIGHT_SENSOR_LUX && board::HAS_LIGHT_SENSOR && !ambient::fault()
+                    });
+                    let reference = |lux: &[u8]| {
+                        Some(ambient::CalibrationPoint {
+                            raw: raw?,
+                            lux: f32::from_le_bytes(lux.try_into().ok()?),
+                        })
+                    };
+                    let mut calibration = settings.calibration;
+                    let result = match payload {
+                        [protocol::CALIBRATION_CLEAR] => {
+                            calibration = ambient::Calibration::default();
+                            Ok(())
+                        }
+                        [protocol::CALIBRATION_DARK, lux @ ..] => reference(lux)
+                            .map(|point| calibration.dark = Some(point))
+                            .ok_or(rgb_matrix::Error),
+                        [protocol::CALIBRATION_BRIGHT, lux @ ..] => reference(lux)
+                            .map(|point| calibration.bright = Some(point))
+                            .ok_or(rgb_matrix::Error),
+                        _ => Err(rgb_matrix::Error),
+                    };
+                    result.map(|()| {
+                        info!("calibration: {}", calibration);
+                        settings.calibration = calibration;
+                        settings.save();
+                    })
+                }
+                protocol::Command::BrightnessMode => {
+                    settings::decode_mode(&mut settings::Reader::new(payload))
+                        .map(|mode| {
+                            brightness.set_mode(mode);
+                            settings.mode = mode;
+                            settings.save();
+                        })
+                        .ok_or(rgb_matrix::Error)
+                }
+                protocol::Command::Schedule => {
+                    settings::decode_schedule(&mut settings::Reader::new(payload))
+                        .map(|schedule| {
+                            brightness.set_schedule(schedule);
+                            settings.schedule = schedule;
+                            settings.save();
+                        })
+                        .ok_or(rgb_matrix::Error)
+                }
+                protocol::Command::SetTime => match payload {
+                    [hour, minute, second] => rtc
+                        .set_datetime(hal::rtc::DateTime {
+                            year: 2000,
+                            month: 1,
+                            day: 1,
+                            day_of_week: hal::rtc::DayOfWeek::Saturday,
+                            hour: *hour,
+                            minute: *minute,
+                            second: *second,
+                        })
+                        .map(|()| time_set = true)
+                        .map_err(|_| rgb_matrix::Error),
+                    _ => Err(rgb_matrix::Error),
+                },
+            };
+            if result.is_err() {
+                warn!("dropped invalid {} message", message.command);
+            }
+
+            sio.fifo.write(message.buffer as u32);
+        }
+
+        if let Some(pattern) = test_pattern.as_mut() {
+            let now = timer.get_counter();
+            if (now - last_test_step).to_micros() >= TEST_PATTERN_STEP_US {
+                let test_button_low = test_button.is_low().unwrap();
+                if test_button_low && !test_button_was_low {
+                    *pattern = pattern.next();
+                    test_step = 0;
+                    info!("test pattern: {}", *pattern);
+                }
+                test_button_was_low = test_button_low;
+
+                test_patterns::draw(*pattern, test_step, &mut test_frame);
+                matrix.swap_next_frame(&mut test_frame);
+                test_step += 1;
+                last_test_step = now;
+            }
         }
 
-        // // Read the brightness sensor and store the value in the array
-        let brightness_sensor_value: u16 = adc.read(&mut brightness_sensor_adc).unwrap();
+        if let (Some(guard), Some(temperature)) = (thermal_guard.as_mut(), ambient::temperature()) {
+            guard.update(temperature);
+            if guard.max_level() != brightness.max_level() {
+                warn!(
+                    "temperature {} C, brightness capped at level {}",
+                    temperature,
+                    guard.max_level()
+                );
+                brightness.set_max_level(guard.max_level());
+            }
 
-        // // Update the brightness
-        brightness = BRIGHTNESS_EXP_ALPHA * brightness
-            + (1.0 - BRIGHTNESS_EXP_ALPHA) * brightness_sensor_value as f32;
+            if guard.slow_clock() != clock_slowed {
+                clock_slowed = guard.slow_clock();
+                warn!("temperature {} C, slow clock {}", temperature, clock_slowed);
+            }
+        }
+
+        let now = rtc.now().ok().filter(|_| time_set);
+        brightness.set_minute_of_day(now.map(|now| now.hour as u16 * 60 + now.minute as u16));
+
+        // Boards without a sensor stay at full brightness.
+        if let Some(ambient) = ambient::ambient() {
+            brightness.set_ambient(settings.calibration.apply(ambient));
+        }
+        if ambient::fault() != sensor_fault {
+            sensor_fault = ambient::fault();
+            brightness.set_fault(sensor_fault);
+            if sensor_fault {
+                warn!(
+                    "light sensor fault, using brightness level {}",
+                    brightness.level()
+                );
+            } else {
+                info!("light sensor recovered");
+            }
+        }
+
+        // The supply protection acts at once instead of ramping, a sagging
+        // supply can't wait.
+        if let (Some(guard), Some(volts)) = (supply_guard.as_mut(), ambient::supply_voltage()) {
+            guard.update(volts);
+            if (guard.max_level(), guard.blank()) != (supply_max_level, supply_blank) {
+                supply_max_level = guard.max_level();
+                supply_blank = guard.blank();
+                if supply_blank {
+                    warn!("supply {} V, panel blanked", volts);
+                } else {
+                    warn!(
+                        "supply {} V, brightness capped at level {}",
+                        volts, supply_max_level
+                    );
+                }
+            }
+        }
+
+        // Go idle when there is nothing to show. Test patterns keep the panel on.
+        let now = timer.get_counter();
+        if !matrix.is_black() || test_pattern.is_some() {
+            last_lit = now;
+        }
+        let black = IDLE_BLACK_US.is_some_and(|timeout| (now - last_lit).to_micros() >= timeout);
+        let no_input = test_pattern.is_none()
+            && IDLE_INPUT_TIMEOUT_US
+                .is_some_and(|timeout| (now - last_input).to_micros() >= timeout);
+        if (black || no_input) != idle {
+            idle = black || no_input;
+            info!("idle: {}", idle);
+            if IDLE_PANEL_POWER_OFF {
+                panel_power.set_state((!idle).into()).unwrap();
+            }
+        }
+
+        // Glitch free, the refresh rate drops with the clock
+        let divider = if idle { IDLE_CLOCK_DIVIDER.max(1) } else { 1 };
+        let divider = if clock_slowed {
+            divider.max(2)
+        } else {
+            divider
+        };
+        if divider != clock_divider {
+            clock_divider = divider;
+            clocks
+                .system_clock
+                .configure_clock(&pll_sys, pll_sys.operating_frequency() / divider)
+                .unwrap();
+            matrix.set_system_clock(clocks.system_clock.freq());
+        }
 
         // Render the matrix
-        matrix.render(brightness_n(brightness as u16));
-    }
-}
+        let output = brightness.output(timer.get_counter().ticks());
+        if !supply_blank && !idle {
+            matrix.render(output.min(supply_max_level as u16 * rgb_matrix::LEVEL_STEPS));
+        } else {
+            // The output is off between refreshes. Sleep until the next sensor
+            // sample or message from core 1.
+            cortex_m::asm::wfe();
+        }
 
-fn brightness_n(adc: u16) -> u8 {
-    if adc < 50 {
-        1
-    } else if adc < 100 {
-        2
-    } else if adc < 200 {
-        3
-    } else if adc < 400 {
-        4
-    } else if adc < 700 {
-        5
-    } else if adc < 1200 {
-        6
-    } else {
-        7
+        let now = timer.get_counter();
+        if (now - last_refresh_log).to_micros() >= REFRESH_LOG_INTERVAL_US {
+            let stats = matrix.refresh_stats();
+            info!(
+                "refresh: {} Hz, frame {} us, convert {} us, wait {} us, planes {} us",
+                stats.refresh_hz, stats.frame_us, stats.convert_us, stats.wait_us, stats.plane_us
+            );
+
+            info!(
+                "brightness: level {}, mode {}, ambient {}",
+                brightness.level(),
+                brightness.mode(),
+                brightness.ambient()
+            );
+
+            if let Some(temperature) = ambient::temperature() {
+                info!("temperature: {} C", temperature);
+            }
+            if let Some(volts) = ambient::supply_voltage() {
+                info!("supply: {} V", volts);
+            }
+
+            let power = matrix.power_status();
+            if power.limited {
+                warn!(
+                    "power: {} mA, brightness limited from {} to {}",
+                    power.estimate_ma, power.requested_brightness, power.brightness
+                );
+            } else if POWER_LIMIT.is_some() {
+                info!("power: {} mA", power.estimate_ma);
+            }
+            last_refresh_log = now;
+        }
     }
 }
 
@@ -0,0 +1,56 @@
+// Path: src/overlay.rs
+//
+// A tiny 3x5 font for drawing status numbers on the panel.
+
+const DIGIT_WIDTH: usize = 3;
+const DIGIT_HEIGHT: usize = 5;
+
+// One row per byte, most significant of the three bits is the leftmost pixel.
+const DIGITS: [[u8; DIGIT_HEIGHT]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b011, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b010, 0b010, 0b010],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+
+// Size of the box `draw_number` fills for the given number of digits,
+// including a one pixel border.
+pub fn number_size(digits: usize) -> (usize, usize) {
+    (digits * (DIGIT_WIDTH + 1) + 1, DIGIT_HEIGHT + 2)
+}
+
+// Draws `value` right aligned in a box of `digits` characters with its top left
+// corner at (0, 0). `set_pixel` is called for every pixel of the box with
+// whether it is lit.
+pub fn draw_number(value: u32, digits: usize, mut set_pixel: impl FnMut(usize, usize, bool)) {
+    let (width, height) = number_size(digits);
+    for y in 0..height {
+        for x in 0..width {
+            set_pixel(x, y, false);
+        }
+    }
+
+    let mut value = value;
+    for position in (0..digits).rev() {
+        let glyph = DIGITS[(value % 10) as usize];
+        let left = 1 + position * (DIGIT_WIDTH + 1);
+        for (row, bits) in glyph.iter().enumerate() {
+            for col in 0..DIGIT_WIDTH {
+                if bits & (0b100 >> col) != 0 {
+                    set_pixel(left + col, 1 + row, true);
+                }
+            }
+        }
+
+        value /= 10;
+        if value == 0 {
+            break;
+        }
+    }
+}
@@ -0,0 +1,216 @@
+// Path: src/protocol.rs
+//
+// Framing of the SPI input. Every message is
+//
+//   0xA5, command, payload length (u16, little endian), payload
+//
+// Bytes between messages are ignored, so after a glitch the receiver picks up
+// again at the next sync byte.
+use crate::rgb_matrix::{PixelFormat, FRAME_BYTES};
+
+pub const SYNC: u8 = 0xA5;
+// A rectangle header followed by a whole RGB888 frame is the largest payload.
+pub const MAX_PAYLOAD: usize = RECT_HEADER + FRAME_BYTES;
+// Pixel format code, x, y, width and height, one byte each.
+pub const RECT_HEADER: usize = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Command {
+    // Pixel format code, then the pixels of a whole frame in that format.
+    Frame = 0x01,
+    // Up to 256 RGB888 entries for the indexed pixel format.
+    Palette = 0x02,
+    // Rectangle header (see `RECT_HEADER`), then its pixels row by row.
+    Rect = 0x03,
+    // A test pattern number to show instead of the input, or 0xFF to stop.
+    TestPattern = 0x04,
+    // Brightness curve settings, see `settings::decode_brightness_message`. They are
+    // stored in flash.
+    BrightnessConfig = 0x05,
+    // Takes the current analog sensor reading as a calibration reference:
+    // `CALIBRATION_DARK` or `CALIBRATION_BRIGHT`, then the lux it stands for
+    // (f32, little endian). `CALIBRATION_CLEAR` alone drops both references.
+    // Stored in flash.
+    Calibrate = 0x06,
+    // Brightness mode, see `settings::encode_mode`. Stored in flash.
+    BrightnessMode = 0x07,
+    // Brightness schedule, see `settings::encode_schedule`. Stored in flash.
+    Schedule = 0x08,
+    // Time of day for the schedule: hour, minute and second. It is kept until
+    // the next reset.
+    SetTime = 0x09,
+}
+
+impl Command {
+    fn from_u8(value: u8) -> Option<Command> {
+        match value {
+            0x01 => Some(Command::Frame),
+            0x02 => Some(Command::Palette),
+            0x03 => Some(Command::Rect),
+            0x04 => Some(Command::TestPattern),
+            0x05 => Some(Command::BrightnessConfig),
+            0x06 => Some(Command::Calibrate),
+            0x07 => Some(Command::BrightnessMode),
+            0x08 => Some(Command::Schedule),
+            0x09 => Some(Command::SetTime),
+            _ => None,
+        }
+    }
+}
+
+// Test pattern payload that goes back to showing the input.
+pub const TEST_PATTERN_OFF: u8 = 0xFF;
+
+// Calibration payloads.
+pub const CALIBRATION_DARK: u8 = 0x00;
+pub const CALIBRATION_BRIGHT: u8 = 0x01;
+pub const CALIBRATION_CLEAR: u8 = 0xFF;
+
+// Pixel format codes used in frame messages.
+pub fn pixel_format(code: u8) -> Option<PixelFormat> {
+    match code {
+        0x00 => Some(PixelFormat::Rgb888),
+        0x01 => Some(PixelFormat::Bgr888),
+        0x02 => Some(PixelFormat::Rgb565),
+        0x03 => Some(PixelFormat::Gray8),
+        0x04 => Some(PixelFormat::Indexed8),
+        _ => None,
+    }
+}
+
+// A received message waiting in one of the input buffers. It is passed from
+// the input core to the render core as a single SIO FIFO word.
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub struct Message {
+    pub command: Command,
+    pub buffer: usize,
+    pub length: usize,
+}
+
+impl Message {
+    pub fn to_word(self) -> u32 {
+        (self.command as u32) << 24 | (self.buffer as u32) << 16 | self.length as u32
+    }
+
+    pub fn from_word(word: u32) -> Option<Message> {
+        Some(Message {
+            command: Command::from_u8((word >> 24) as u8)?,
+            buffer: ((word >> 16) & 0xFF) as usize,
+            length: (word & 0xFFFF) as usize,
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Sync,
+    Command,
+    LengthLow,
+    LengthHigh,
+    Payload,
+    // Payload of a message we don't understand or can't hold.
+    Skip,
+}
+
+pub struct Parser {
+    state: State,
+    command: Option<Command>,
+    length: usize,
+    position: usize,
+}
+
+impl Parser {
+    pub const fn new() -> Parser {
+        Parser {
+            state: State::Sync,
+            command: None,
+            length: 0,
+            position: 0,
+        }
+    }
+
+    // Feeds one received byte, storing the payload in `buffer`. Returns the
+    // command and payload length once a whole message has arrived. An empty
+    // `buffer` means none is free, so messages are dropped, even those without
+    // a payload.
+    pub fn push(&mut self, byte: u8, buffer: &mut [u8]) -> Option<(Command, usize)> {
+        match self.state {
+            State::Sync => {
+                if byte == SYNC {
+                    self.state = State::Command;
+                }
+            }
+            State::Command => {
+                self.command = Command::from_u8(byte);
+                self.state = State::LengthLow;
+            }
+            State::LengthLow => {
+                self.length = byte as usize;
+                self.state = State::LengthHigh;
+            }
+            State::LengthHigh => {
+                self.length |= (byte as usize) << 8;
+                self.position = 0;
+                let fits = !buffer.is_empty() && self.length <= buffer.len();
+                self.state = match self.command {
+                    Some(command) if fits && self.length == 0 => {
+                        self.state = State::Sync;
+                        return Some((command, 0));
+                    }
+                    Some(_) if fits => State::Payload,
+                    _ if self.length == 0 => State::Sync,
+                    _ => State::Skip,
+                };
+            }
+            State::Payload => {
+                buffer[self.position] = byte;
+                self.position += 1;
+
+                if self.position == self.length {
+                    self.state = State::Sync;
+                    return self.command.map(|command| (command, self.length));
+                }
+            }
+            State::Skip => {
+                self.position += 1;
+
+                if self.position == self.length {
+                    self.state = State::Sync;
+                }
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push_all(parser: &mut Parser, bytes: &[u8], buffer: &mut [u8]) -> Option<(Command, usize)> {
+        bytes
+            .iter()
+            .fold(None, |_, &byte| parser.push(byte, buffer))
+    }
+
+    #[test]
+    fn empty_message_is_returned_with_a_free_buffer() {
+        let mut parser = Parser::new();
+        let mut buffer = [0; 4];
+        let message = push_all(&mut parser, &[SYNC, 0x04, 0, 0], &mut buffer);
+        assert_eq!(message, Some((Command::TestPattern, 0)));
+    }
+
+    #[test]
+    fn empty_message_is_dropped_without_a_free_buffer() {
+        let mut parser = Parser::new();
+        assert_eq!(push_all(&mut parser, &[SYNC, 0x04, 0, 0], &mut []), None);
+
+        // The parser is back in sync for the next message.
+        let mut buffer = [0; 4];
+        let message = push_all(&mut parser, &[SYNC, 0x04, 1, 0, 2], &mut buffer);
+        assert_eq!(message, Some((Command::TestPattern, 1)));
+        assert_eq!(buffer[0], 2);
+    }
+}
@@ -1,10 +1,29 @@
 // Path: src/rgb_matrix.rs
+use crate::bsp::hal::Timer;
+use crate::framebuffer::FrameBuffer;
+use crate::overlay;
 use cortex_m::asm;
 use embedded_hal::digital::v2::OutputPin;
+use fugit::HertzU32;
+use libm::powf;
 
 const WIDTH: usize = 96;
 const HEIGHT: usize = 48;
 const HALF_HEIGHT: usize = HEIGHT / 2;
+pub const FRAME_PIXELS: usize = WIDTH * HEIGHT;
+pub const FRAME_BYTES: usize = FRAME_PIXELS * 3;
+const REFRESH_OVERLAY_DIGITS: usize = 4;
+// Resolution of the gamma tables, and so the most bitplanes we can show.
+const PWM_BITS: usize = 11;
+// Temporal dithering cycles through this many refreshes. The order spreads the
+// lit refreshes of each level as evenly as possible over the cycle.
+const DITHER_PHASES: usize = 8;
+const DITHER_ORDER: [u8; DITHER_PHASES] = [0, 4, 2, 6, 1, 5, 3, 7];
+// The longest bitplane can be split into at most this many pieces.
+const MAX_PLANE_SPLITS: usize = 8;
+// Rough number of cycles it takes to shift out and latch one line, for
+// estimating how much of a refresh the LEDs are on.
+const LINE_SHIFT_CYCLES: u64 = 3000;
 const DELAY_TABLE_8: [u32; 11] = [6, 12, 24, 48, 96, 192, 384, 768, 1536, 3072, 6144];
 const DELAY_TABLE_7: [u32; 11] = [5, 10, 20, 40, 80, 160, 320, 640, 1280, 2560, 5120];
 const DELAY_TABLE_6: [u32; 11] = [4, 8, 16, 32, 64, 128, 256, 512, 1024, 2048, 4096];
@@ -16,6 +35,13 @@ const DELAY_TABLE_1: [u32; 11] = [
     6144, 6144, 6144, 6144, 6144, 6144, 6144, 6144, 6144, 6144, 6144,
 ];
 // const DELAY_TABLE: [u32; 11] = [1, 2, 4, 8, 16, 32, 64, 128, 256, 512, 1024];
+// The brightness passed to `render` is in steps of 1/LEVEL_STEPS of a level.
+pub const LEVEL_STEPS: u16 = 256;
+// The lowest levels also scale the pixel values down before gamma, by a factor
+// in 1/SCALE_FULL.
+const SCALE_BITS: u32 = 6;
+const SCALE_FULL: u16 = 1 << SCALE_BITS;
+const MAX_LEVEL: u8 = 7;
 const GAMMA_RED_TABLE: [u16; 256] = [
     // 2.9 gamma
     0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 4, 4, 5,
@@ -68,6 +94,81 @@ const GAMMA_BLUE_TABLE: [u16; 256] = [
 pub struct Error;
 pub type Result<T> = core::result::Result<T, Error>;
 
+// Which colour each of the panel's R, G and B inputs actually drives. A GRB
+// panel lights green on its R input, so `Grb` routes the green channel there.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum ChannelOrder {
+    Rgb,
+    Rbg,
+    Grb,
+    Gbr,
+    Brg,
+    Bgr,
+}
+
+impl ChannelOrder {
+    // Source channel (0 = red, 1 = green, 2 = blue) for the R, G and B inputs.
+    fn source_channels(self) -> [usize; 3] {
+        match self {
+            ChannelOrder::Rgb => [0, 1, 2],
+            ChannelOrder::Rbg => [0, 2, 1],
+            ChannelOrder::Grb => [1, 0, 2],
+            ChannelOrder::Gbr => [1, 2, 0],
+            ChannelOrder::Brg => [2, 0, 1],
+            ChannelOrder::Bgr => [2, 1, 0],
+        }
+    }
+}
+
+// Panel quirks that are handled in the driver, so frame producers can always
+// send plain RGB.
+#[derive(Clone, Copy, Debug)]
+pub struct PanelConfig {
+    pub channel_order: ChannelOrder,
+    // A 1 on the data lines turns the LED off.
+    pub invert_data: bool,
+    // OE is active high instead of active low.
+    pub invert_output_enable: bool,
+    // The latch takes a low pulse instead of a high pulse.
+    pub invert_latch: bool,
+    // Nanoseconds to keep the output blanked before changing the row address
+    // and after latching, to let the row drivers settle. Raise these if the
+    // previous row ghosts.
+    pub blank_before_address_ns: u32,
+    pub blank_after_latch_ns: u32,
+    // Latch a black line before switching rows, so the columns are dark while
+    // the row drivers switch over. Costs an extra line shift per row.
+    pub precharge_black_line: bool,
+}
+
+// Pixel formats accepted by `set_next_frame_format`. Frames are converted to
+// RGB888 when they are swapped in, so the format doesn't affect rendering.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum PixelFormat {
+    Rgb888,
+    Bgr888,
+    // Big endian, 5 bits red, 6 bits green, 5 bits blue.
+    Rgb565,
+    Gray8,
+    // One byte per pixel indexing the palette set with `set_palette`.
+    Indexed8,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Rgb888 | PixelFormat::Bgr888 => 3,
+            PixelFormat::Rgb565 => 2,
+            PixelFormat::Gray8 | PixelFormat::Indexed8 => 1,
+        }
+    }
+
+    pub fn frame_bytes(self) -> usize {
+        FRAME_PIXELS * self.bytes_per_pixel()
+    }
+}
+
 pub struct RgbPins<
     R0: OutputPin,
     G0: OutputPin,
@@ -208,6 +309,126 @@ impl<A: OutputPin, B: OutputPin, C: OutputPin, D: OutputPin, E: OutputPin> AddrP
     }
 }
 
+// Row selection of the panel, like `--led-row-addr-type` in rpi-rgb-led-matrix.
+// `row` is the scan line that is about to be latched.
+pub trait RowAddress {
+    // How many scan lines can be selected. Panels with fewer scan lines than
+    // HALF_HEIGHT share each address between several rows per half, which
+    // have to divide evenly.
+    const SCAN_LINES: usize;
+
+    fn select_row(&mut self, row: usize) -> Result<()>;
+}
+
+// Direct binary addressing on A-E, what most panels use.
+impl<A: OutputPin, B: OutputPin, C: OutputPin, D: OutputPin, E: OutputPin> RowAddress
+    for AddrPins<A, B, C, D, E>
+{
+    const SCAN_LINES: usize = 32;
+
+    fn select_row(&mut self, row: usize) -> Result<()> {
+        self.set_addr_bits(row as u8)
+    }
+}
+
+// Binary addressing on A-C only, for 1/8 scan panels that leave D and E
+// unconnected. Those light rows 8 and 16 apart together, so the matrix shifts
+// three rows per half for each address. Like the other addressing types it is
+// only used when board.rs picks it.
+#[allow(dead_code)]
+pub struct AbcAddrPins<A: OutputPin, B: OutputPin, C: OutputPin> {
+    pub a: A,
+    pub b: B,
+    pub c: C,
+}
+
+#[allow(dead_code)]
+impl<A: OutputPin, B: OutputPin, C: OutputPin> AbcAddrPins<A, B, C> {
+    pub fn new(a: A, b: B, c: C) -> AbcAddrPins<A, B, C> {
+        AbcAddrPins { a, b, c }
+    }
+}
+
+impl<A: OutputPin, B: OutputPin, C: OutputPin> RowAddress for AbcAddrPins<A, B, C> {
+    const SCAN_LINES: usize = 8;
+
+    fn select_row(&mut self, row: usize) -> Result<()> {
+        if row & 0b0000_0001 != 0 {
+            self.a.set_high().map_err(|_| Error)?;
+        } else {
+            self.a.set_low().map_err(|_| Error)?;
+        }
+
+        if row & 0b0000_0010 != 0 {
+            self.b.set_high().map_err(|_| Error)?;
+        } else {
+            self.b.set_low().map_err(|_| Error)?;
+        }
+
+        if row & 0b0000_0100 != 0 {
+            self.c.set_high().map_err(|_| Error)?;
+        } else {
+            self.c.set_low().map_err(|_| Error)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Shift register row drivers (DP32020, 74HC164 and similar) where A is the
+// data input and B the shift clock. The selected row is a single low bit that
+// is clocked through the register, so stepping to the next row only needs one
+// clock pulse.
+#[allow(dead_code)]
+pub struct ShiftRegisterAddrPins<Data: OutputPin, Clock: OutputPin> {
+    pub data: Data,
+    pub clock: Clock,
+    last_row: Option<usize>,
+}
+
+#[allow(dead_code)]
+impl<Data: OutputPin, Clock: OutputPin> ShiftRegisterAddrPins<Data, Clock> {
+    pub fn new(data: Data, clock: Clock) -> ShiftRegisterAddrPins<Data, Clock> {
+        ShiftRegisterAddrPins {
+            data,
+            clock,
+            last_row: None,
+        }
+    }
+
+    fn shift_bit(&mut self, active: bool) -> Result<()> {
+        self.clock.set_low().map_err(|_| Error)?;
+        if active {
+            self.data.set_low().map_err(|_| Error)?;
+        } else {
+            self.data.set_high().map_err(|_| Error)?;
+        }
+        asm::nop();
+        self.clock.set_high().map_err(|_| Error)?;
+        Ok(())
+    }
+}
+
+impl<Data: OutputPin, Clock: OutputPin> RowAddress for ShiftRegisterAddrPins<Data, Clock> {
+    const SCAN_LINES: usize = HALF_HEIGHT;
+
+    fn select_row(&mut self, row: usize) -> Result<()> {
+        match self.last_row {
+            Some(last) if last == row => {}
+            Some(last) if last + 1 == row => self.shift_bit(false)?,
+            _ => {
+                // Shift the whole register so only `row` is active
+                for position in (0..HALF_HEIGHT).rev() {
+                    self.shift_bit(position == row)?;
+                }
+            }
+        }
+        self.last_row = Some(row);
+
+        Ok(())
+    }
+}
+
 pub struct LatchPin<L: OutputPin> {
     pub latch: L,
 }
@@ -268,12 +489,310 @@ impl<O: OutputPin> OutputEnablePin<O> {
     }
 }
 
-fn brightness_adjust(frame: &mut [u8], brightness: u8) {
+fn gamma(channel: usize, value: u8) -> u16 {
+    match channel {
+        0 => GAMMA_RED_TABLE[value as usize],
+        1 => GAMMA_GREEN_TABLE[value as usize],
+        _ => GAMMA_BLUE_TABLE[value as usize],
+    }
+}
+
+// Converts one pixel in `format` to RGB888.
+fn decode_pixel(format: PixelFormat, palette: &[u8; 256 * 3], data: &[u8]) -> [u8; 3] {
+    match format {
+        PixelFormat::Rgb888 => [data[0], data[1], data[2]],
+        PixelFormat::Bgr888 => [data[2], data[1], data[0]],
+        PixelFormat::Rgb565 => {
+            let value = u16::from_be_bytes([data[0], data[1]]);
+            let r = ((value >> 11) & 0x1F) as u8;
+            let g = ((value >> 5) & 0x3F) as u8;
+            let b = (value & 0x1F) as u8;
+            [r << 3 | r >> 2, g << 2 | g >> 4, b << 3 | b >> 2]
+        }
+        PixelFormat::Gray8 => [data[0], data[0], data[0]],
+        PixelFormat::Indexed8 => {
+            let entry = 3 * data[0] as usize;
+            [palette[entry], palette[entry + 1], palette[entry + 2]]
+        }
+    }
+}
+
+fn gray_palette() -> [u8; 256 * 3] {
+    let mut palette = [0; 256 * 3];
+    for (index, entry) in palette.chunks_exact_mut(3).enumerate() {
+        entry.fill(index as u8);
+    }
+    palette
+}
+
+// Rounds an 11 bit gamma value to the top `bits` bits.
+fn reduce_depth(value: u16, bits: usize) -> u16 {
+    let shift = PWM_BITS - bits;
+    if shift == 0 {
+        return value;
+    }
+    ((value + (1 << (shift - 1))) >> shift).min((1 << bits) - 1)
+}
+
+// Gamma of `value` scaled by `brightness_scale` before gamma, in 1/SCALE_FULL.
+// Where the scaled value falls between two gamma table entries the result is
+// interpolated.
+fn scaled_gamma(channel: usize, value: u8, brightness_scale: u16) -> u32 {
+    let scaled = value as u32 * brightness_scale as u32;
+    let index = (scaled >> SCALE_BITS) as u8;
+    let low = gamma(channel, index) as u32;
+    let high = gamma(channel, index.saturating_add(1)) as u32;
+    (low << SCALE_BITS) + (high - low) * (scaled & (SCALE_FULL as u32 - 1))
+}
+
+// With temporal dithering the fraction of the scaled gamma value is kept,
+// which leaves fractional bits below the least significant bitplane. Returns
+// the value to show and the dither level (0-7) for the least significant
+// plane, which is twice the LSB plus the top two fractional bits.
+fn dither_value(channel: usize, value: u8, brightness_scale: u16, bits: usize) -> (u16, u8) {
+    let scaled = scaled_gamma(channel, value, brightness_scale);
+    let shift = SCALE_BITS as usize + PWM_BITS - bits;
+    let integer = (scaled >> shift) as u16;
+    let quarters = ((scaled >> (shift - 2)) & 0b11) as u8;
+
+    (integer, ((integer & 0x01) as u8) << 2 | quarters)
+}
+
+fn brightness_scale(brightness: u8) -> u16 {
     if brightness > 2 {
-        return;
+        SCALE_FULL
+    } else {
+        brightness as u16 * SCALE_FULL / 8
+    }
+}
+
+fn delay_table(level: u8) -> [u32; PWM_BITS] {
+    match level {
+        0 => DELAY_TABLE_1,
+        1 => DELAY_TABLE_2,
+        2 => DELAY_TABLE_3,
+        3 => DELAY_TABLE_4,
+        4 => DELAY_TABLE_5,
+        5 => DELAY_TABLE_6,
+        6 => DELAY_TABLE_7,
+        7 => DELAY_TABLE_8,
+        _ => DELAY_TABLE_8,
+    }
+}
+
+// Relative light output of a full green pixel with the delay table of
+// `level` and the pixels scaled by `brightness_scale`. The scale goes in
+// before gamma, so it changes the output far more than its own ratio.
+fn level_output(level: u8, brightness_scale: u16) -> f32 {
+    let on: u32 = delay_table(level).iter().sum();
+    on as f32 * scaled_gamma(1, 255, brightness_scale) as f32
+}
+
+// How to show a brightness between two levels: the level whose delay table is
+// used, the factor its delays are stretched by in 1/256, and the brightness
+// scale of the pixels. The light output goes from one level to the next on a
+// log scale, and linearly up from black.
+//
+// Between the lowest levels the pixel scale changes as well, and stretching
+// the delays alone can't cover that. There the scale is ramped on a log scale
+// too, with the upper level's delay table, and the stretch makes up for the
+// steps of the scale. Every change of the scale converts the frame again.
+fn level_stretch(brightness: u16) -> (u8, u32, u16) {
+    let lower = (brightness / LEVEL_STEPS) as u8;
+    let fraction = (brightness % LEVEL_STEPS) as f32 / LEVEL_STEPS as f32;
+    if fraction == 0.0 || lower >= MAX_LEVEL {
+        let level = lower.min(MAX_LEVEL);
+        return (level, 256, brightness_scale(level));
+    }
+
+    let upper = lower + 1;
+    let (lower_scale, upper_scale) = (brightness_scale(lower), brightness_scale(upper));
+    let lower_output = level_output(lower, lower_scale);
+    if lower_output == 0.0 {
+        return (upper, (fraction * 256.0) as u32, upper_scale);
+    }
+    let ratio = level_output(upper, upper_scale) / lower_output;
+    let output = lower_output * powf(ratio, fraction);
+    if lower_scale == upper_scale {
+        return (lower, (output / lower_output * 256.0) as u32, lower_scale);
+    }
+
+    let scale_ratio = upper_scale as f32 / lower_scale as f32;
+    let scale = ((lower_scale as f32 * powf(scale_ratio, fraction)) as u16).max(lower_scale);
+    let stretch = output / level_output(upper, scale) * 256.0;
+    (upper, stretch as u32, scale)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum Rotation {
+    Deg0,
+    Deg90,
+    Deg180,
+    Deg270,
+}
+
+// How frames are placed on the panel. The frame is rotated clockwise first and
+// then mirrored, so a transposed image is `Deg90` plus `mirror_horizontal`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Orientation {
+    pub rotation: Rotation,
+    pub mirror_horizontal: bool,
+    pub mirror_vertical: bool,
+}
+
+impl Default for Orientation {
+    fn default() -> Self {
+        Orientation {
+            rotation: Rotation::Deg0,
+            mirror_horizontal: false,
+            mirror_vertical: false,
+        }
+    }
+}
+
+impl Orientation {
+    // Width and height of the frames the matrix expects in this orientation.
+    pub fn frame_size(&self) -> (usize, usize) {
+        match self.rotation {
+            Rotation::Deg0 | Rotation::Deg180 => (WIDTH, HEIGHT),
+            Rotation::Deg90 | Rotation::Deg270 => (HEIGHT, WIDTH),
+        }
+    }
+
+    // Maps a panel pixel to the index of the pixel in the frame.
+    fn frame_pixel(&self, x: usize, y: usize) -> usize {
+        let x = if self.mirror_horizontal {
+            WIDTH - 1 - x
+        } else {
+            x
+        };
+        let y = if self.mirror_vertical {
+            HEIGHT - 1 - y
+        } else {
+            y
+        };
+
+        let (frame_x, frame_y) = match self.rotation {
+            Rotation::Deg0 => (x, y),
+            Rotation::Deg90 => (y, WIDTH - 1 - x),
+            Rotation::Deg180 => (WIDTH - 1 - x, HEIGHT - 1 - y),
+            Rotation::Deg270 => (HEIGHT - 1 - y, x),
+        };
+        let (frame_width, _) = self.frame_size();
+
+        frame_y * frame_width + frame_x
     }
-    for i in 0..frame.len() {
-        frame[i] = (frame[i] as u16 * brightness as u16 / 8) as u8;
+
+    // The inverse of `frame_pixel`, maps a frame position to a panel pixel.
+    fn panel_pixel(&self, frame_x: usize, frame_y: usize) -> (usize, usize) {
+        let (x, y) = match self.rotation {
+            Rotation::Deg0 => (frame_x, frame_y),
+            Rotation::Deg90 => (WIDTH - 1 - frame_y, frame_x),
+            Rotation::Deg180 => (WIDTH - 1 - frame_x, HEIGHT - 1 - frame_y),
+            Rotation::Deg270 => (frame_y, HEIGHT - 1 - frame_x),
+        };
+        let x = if self.mirror_horizontal {
+            WIDTH - 1 - x
+        } else {
+            x
+        };
+        let y = if self.mirror_vertical {
+            HEIGHT - 1 - y
+        } else {
+            y
+        };
+
+        (x, y)
+    }
+}
+
+// Keeps the estimated panel current below a budget by lowering the brightness.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PowerLimit {
+    // Current of a single red, green and blue LED while it is on.
+    pub led_ma: [u32; 3],
+    // Use u32::MAX to only estimate the current without limiting.
+    pub budget_ma: u32,
+}
+
+#[derive(Clone, Copy, Debug, Default, defmt::Format)]
+pub struct PowerStatus {
+    // Estimated average panel current at the brightness actually shown, 0
+    // without a power limit.
+    pub estimate_ma: u32,
+    pub requested_brightness: u8,
+    pub brightness: u8,
+    // The brightness was lowered to stay within the budget.
+    pub limited: bool,
+}
+
+// Timing of the last refresh, measured with the 1 us timer.
+#[derive(Clone, Copy, Debug, Default, defmt::Format)]
+pub struct RefreshStats {
+    // Full refreshes per second, from the time between two scan outs.
+    pub refresh_hz: u32,
+    // Time between the start of the last two scan outs.
+    pub frame_us: u32,
+    // Time spent converting a new frame into bitplanes before the scan out, 0
+    // if there was none.
+    pub convert_us: u32,
+    // Time spent waiting for the refresh limit before the last refresh.
+    pub wait_us: u32,
+    // Time spent scanning out each bitplane.
+    pub plane_us: [u32; PWM_BITS],
+}
+
+// Order in which the bitplanes are scanned out. Without splitting it is every
+// plane once, LSB first. With splitting the long planes are cut into pieces no
+// longer than the longest plane divided by `splits`, and the pieces are spread
+// over `splits` rounds so the long on times are interleaved with the short
+// planes instead of coming in one block. The total on time per plane is the
+// same either way.
+struct ScanSchedule {
+    // (plane, delay) for each scan of all rows.
+    segments: [(usize, u32); PWM_BITS * MAX_PLANE_SPLITS],
+    len: usize,
+}
+
+impl ScanSchedule {
+    fn new(delays: &[u32], splits: usize) -> ScanSchedule {
+        let mut schedule = ScanSchedule {
+            segments: [(0, 0); PWM_BITS * MAX_PLANE_SPLITS],
+            len: 0,
+        };
+        let longest = delays.iter().copied().max().unwrap_or(0).max(1);
+
+        let mut pieces = [1usize; PWM_BITS];
+        for (pieces, &delay) in pieces.iter_mut().zip(delays.iter()) {
+            *pieces = (delay as usize * splits / longest as usize).clamp(1, splits);
+        }
+
+        for round in 0..splits {
+            for (plane, &delay) in delays.iter().enumerate() {
+                let count = pieces[plane];
+                for piece in 0..count {
+                    // Offset by the plane so single pieces don't all land in
+                    // the first round
+                    if (piece * splits / count + plane) % splits != round {
+                        continue;
+                    }
+
+                    let mut piece_delay = delay / count as u32;
+                    if piece == 0 {
+                        piece_delay += delay % count as u32;
+                    }
+                    schedule.segments[schedule.len] = (plane, piece_delay);
+                    schedule.len += 1;
+                }
+            }
+        }
+
+        schedule
+    }
+
+    fn segments(&self) -> &[(usize, u32)] {
+        &self.segments[..self.len]
     }
 }
 
@@ -284,23 +803,56 @@ pub struct RgbMatrix96x48<
     R1: OutputPin,
     G1: OutputPin,
     B1: OutputPin,
-    A: OutputPin,
-    B: OutputPin,
-    C: OutputPin,
-    D: OutputPin,
-    E: OutputPin,
+    Addr: RowAddress,
     L: OutputPin,
     Clk: OutputPin,
     Oe: OutputPin,
 > {
     rgb_pins: RgbPins<R0, G0, B0, R1, G1, B1>,
-    addr_pins: AddrPins<A, B, C, D, E>,
+    row_address: Addr,
     latch_pin: LatchPin<L>,
     clock_pin: ClockPin<Clk>,
     output_enable_pin: OutputEnablePin<Oe>,
-    current_frame: [u8; 96 * 48 * 3],
-    next_frame: [u8; 96 * 48 * 3],
+    panel: PanelConfig,
+    // The panel blanking times in cycles of the system clock.
+    system_clock_mhz: u32,
+    blank_before_address_cycles: u32,
+    blank_after_latch_cycles: u32,
+    orientation: Orientation,
+    current_frame: [u8; FRAME_BYTES],
+    // Raw frame in `next_format`, converted into `current_frame` on the swap.
+    next_frame: [u8; FRAME_BYTES],
+    next_format: PixelFormat,
+    palette: [u8; 256 * 3],
     swap_frames: bool,
+    // The current frame converted to what is shifted out: one byte of RGB data
+    // bits per column, for every scan line of every bitplane.
+    planes: [[u8; WIDTH * HALF_HEIGHT]; PWM_BITS],
+    planes_brightness_scale: u16,
+    planes_stale: bool,
+    // Every pixel of the converted frame is black.
+    frame_black: bool,
+    // Scan lines changed by `update_rect` since the last conversion, one bit each.
+    dirty_lines: u32,
+    // Sum of the gamma corrected values of the red, green and blue LEDs on
+    // each scan line, at full brightness.
+    line_sums: [[u32; 3]; HALF_HEIGHT],
+    power_limit: Option<PowerLimit>,
+    power: PowerStatus,
+    pwm_bits: usize,
+    // Alternatives for the least significant bitplane, one per refresh of the
+    // dither cycle. It is shown for twice as long as usual, so it can average
+    // anywhere from 0 to 1.75 LSB in quarter steps.
+    dither: bool,
+    dither_planes: [[u8; WIDTH * HALF_HEIGHT]; DITHER_PHASES],
+    dither_phase: usize,
+    plane_splits: usize,
+    timer: &'static Timer,
+    stats: RefreshStats,
+    last_render_start: Option<u32>,
+    show_refresh: bool,
+    refresh_period_us: Option<u32>,
+    next_refresh_start: u64,
 }
 
 impl<
@@ -310,110 +862,696 @@ impl<
         R1: OutputPin,
         G1: OutputPin,
         B1: OutputPin,
-        A: OutputPin,
-        B: OutputPin,
-        C: OutputPin,
-        D: OutputPin,
-        E: OutputPin,
+        Addr: RowAddress,
         L: OutputPin,
         Clk: OutputPin,
         Oe: OutputPin,
-    > RgbMatrix96x48<R0, G0, B0, R1, G1, B1, A, B, C, D, E, L, Clk, Oe>
+    > RgbMatrix96x48<R0, G0, B0, R1, G1, B1, Addr, L, Clk, Oe>
 {
+    // Scan lines actually used, and the rows per half that share one.
+    const SCAN_LINES: usize = if Addr::SCAN_LINES < HALF_HEIGHT {
+        Addr::SCAN_LINES
+    } else {
+        HALF_HEIGHT
+    };
+    const ROWS_PER_SCAN: usize = HALF_HEIGHT / Self::SCAN_LINES;
+
     pub fn new(
         rgb_pins: RgbPins<R0, G0, B0, R1, G1, B1>,
-        addr_pins: AddrPins<A, B, C, D, E>,
+        row_address: Addr,
         latch_pin: LatchPin<L>,
         clock_pin: ClockPin<Clk>,
         output_enable_pin: OutputEnablePin<Oe>,
-    ) -> RgbMatrix96x48<R0, G0, B0, R1, G1, B1, A, B, C, D, E, L, Clk, Oe> {
+        panel: PanelConfig,
+        timer: &'static Timer,
+    ) -> Self {
+        const {
+            assert!(
+                HALF_HEIGHT.is_multiple_of(Self::SCAN_LINES),
+                "the rows of the panel can't be split evenly over the scan lines"
+            )
+        };
+
         RgbMatrix96x48 {
             rgb_pins,
-            addr_pins,
+            row_address,
             latch_pin,
             clock_pin,
             output_enable_pin,
-            current_frame: [0; 96 * 48 * 3],
-            next_frame: [0; 96 * 48 * 3],
+            panel,
+            system_clock_mhz: 0,
+            blank_before_address_cycles: 0,
+            blank_after_latch_cycles: 0,
+            orientation: Orientation::default(),
+            current_frame: [0; FRAME_BYTES],
+            next_frame: [0; FRAME_BYTES],
+            next_format: PixelFormat::Rgb888,
+            palette: gray_palette(),
             swap_frames: false,
+            planes: [[0; WIDTH * HALF_HEIGHT]; PWM_BITS],
+            planes_brightness_scale: SCALE_FULL,
+            planes_stale: true,
+            frame_black: true,
+            dirty_lines: 0,
+            line_sums: [[0; 3]; HALF_HEIGHT],
+            power_limit: None,
+            power: PowerStatus::default(),
+            pwm_bits: PWM_BITS,
+            dither: false,
+            dither_planes: [[0; WIDTH * HALF_HEIGHT]; DITHER_PHASES],
+            dither_phase: 0,
+            plane_splits: 1,
+            timer,
+            stats: RefreshStats::default(),
+            last_render_start: None,
+            show_refresh: false,
+            refresh_period_us: None,
+            next_refresh_start: 0,
+        }
+    }
+
+    // Has to be called with the system clock frequency at startup and whenever
+    // it changes, the panel blanking times are converted to cycles with it.
+    pub fn set_system_clock(&mut self, frequency: HertzU32) {
+        self.system_clock_mhz = frequency.to_MHz();
+        self.update_blank_cycles();
+    }
+
+    fn update_blank_cycles(&mut self) {
+        let cycles = |ns: u32| (ns as u64 * self.system_clock_mhz as u64).div_ceil(1000) as u32;
+        self.blank_before_address_cycles = cycles(self.panel.blank_before_address_ns);
+        self.blank_after_latch_cycles = cycles(self.panel.blank_after_latch_ns);
+    }
+
+    // Takes effect with the next refresh, frames sent from then on have to use
+    // `Orientation::frame_size`.
+    pub fn set_orientation(&mut self, orientation: Orientation) {
+        self.orientation = orientation;
+        self.planes_stale = true;
+    }
+
+    pub fn orientation(&self) -> Orientation {
+        self.orientation
+    }
+
+    // Number of bitplanes shown, like `--led-pwm-bits`. Fewer bits give a higher
+    // refresh rate at the cost of colour depth; brightness stays the same.
+    pub fn set_pwm_bits(&mut self, bits: u8) {
+        let bits = (bits as usize).clamp(1, PWM_BITS);
+        if bits != self.pwm_bits {
+            self.pwm_bits = bits;
+            self.planes_stale = true;
+        }
+    }
+
+    // Spreads the fractional part of dim pixel values over successive
+    // refreshes instead of rounding it away, for smooth gradients at low
+    // brightness levels.
+    pub fn set_dithering(&mut self, enabled: bool) {
+        if enabled != self.dither {
+            self.dither = enabled;
+            self.planes_stale = true;
+        }
+    }
+
+    // Locks the refresh rate to `hz` (e.g. 240, or a multiple of the mains
+    // frequency) so cameras don't pick up rolling bands. Each refresh starts a
+    // fixed period after the previous one, with the output blanked while
+    // waiting, so the limit has to be below the unlimited refresh rate.
+    pub fn set_refresh_limit(&mut self, hz: Option<u32>) {
+        self.refresh_period_us = hz.map(|hz| 1_000_000 / hz.clamp(1, 1_000_000));
+        self.next_refresh_start = self.timer.get_counter().ticks();
+    }
+
+    // Splits the long MSB planes into up to `splits` pieces that are
+    // interleaved with the other planes, which turns the low frequency flicker
+    // of the MSBs into a higher frequency one. 1 scans each plane in one go.
+    // More splits cost refresh rate, as every piece shifts out all rows again.
+    pub fn set_plane_splits(&mut self, splits: u8) {
+        self.plane_splits = (splits as usize).clamp(1, MAX_PLANE_SPLITS);
+    }
+
+    // Lowers the brightness passed to `render` when the estimated current of
+    // the frame would exceed the budget. None disables the limit.
+    pub fn set_power_limit(&mut self, limit: Option<PowerLimit>) {
+        self.power_limit = limit;
+        if limit.is_some() {
+            self.sum_lines(u32::MAX);
+        }
+    }
+
+    pub fn power_status(&self) -> PowerStatus {
+        self.power
+    }
+
+    pub fn refresh_stats(&self) -> RefreshStats {
+        self.stats
+    }
+
+    // Whether the frame last shown by `render` is all black.
+    pub fn is_black(&self) -> bool {
+        self.frame_black
+    }
+
+    // Draws the measured refresh rate in the top left corner of the frame,
+    // like `--led-show-refresh` in rpi-rgb-led-matrix.
+    pub fn set_show_refresh(&mut self, show: bool) {
+        if self.show_refresh && !show {
+            self.planes_stale = true;
         }
+        self.show_refresh = show;
     }
 
-    pub fn set_next_frame(&mut self, data: &[u8]) {
-        self.next_frame.copy_from_slice(data);
+    // Hands a drawn frame to the matrix without copying it. `frame` gets the
+    // buffer of the previous next frame back, so clear it before drawing again.
+    pub fn swap_next_frame(&mut self, frame: &mut FrameBuffer) {
+        core::mem::swap(&mut self.next_frame, &mut frame.data);
+        self.next_format = PixelFormat::Rgb888;
         self.swap_frames = true;
     }
 
-    fn get_data_bits(&mut self, row: usize, col: usize, depth_level: u8) -> u8 {
-        let base_index = 3 * (row * WIDTH + col);
-        let base_index_bottom = 3 * ((row + HALF_HEIGHT) * WIDTH + col);
+    // Copies the frame shown from the next refresh on. `data` has to be exactly
+    // one frame in `format`.
+    pub fn set_next_frame_format(&mut self, data: &[u8], format: PixelFormat) -> Result<()> {
+        if data.len() != format.frame_bytes() {
+            return Err(Error);
+        }
+
+        self.next_frame[..data.len()].copy_from_slice(data);
+        self.next_format = format;
+        self.swap_frames = true;
+        Ok(())
+    }
 
-        let r0 = ((GAMMA_RED_TABLE[self.current_frame[base_index] as usize] >> depth_level) & 0x01)
-            as u8;
-        let g0 = ((GAMMA_GREEN_TABLE[self.current_frame[base_index + 1] as usize] >> depth_level)
-            & 0x01) as u8;
-        let b0 = ((GAMMA_BLUE_TABLE[self.current_frame[base_index + 2] as usize] >> depth_level)
-            & 0x01) as u8;
-        let r1 = ((GAMMA_RED_TABLE[self.current_frame[base_index_bottom] as usize] >> depth_level)
-            & 0x01) as u8;
-        let g1 = ((GAMMA_GREEN_TABLE[self.current_frame[base_index_bottom + 1] as usize]
-            >> depth_level)
-            & 0x01) as u8;
-        let b1 = ((GAMMA_BLUE_TABLE[self.current_frame[base_index_bottom + 2] as usize]
-            >> depth_level)
-            & 0x01) as u8;
+    // Sets palette entries for `PixelFormat::Indexed8`, starting at index 0.
+    // `palette` holds up to 256 RGB888 entries. Applies from the next frame on.
+    pub fn set_palette(&mut self, palette: &[u8]) -> Result<()> {
+        if palette.len() > self.palette.len() || !palette.len().is_multiple_of(3) {
+            return Err(Error);
+        }
 
-        r0 << 0 | g0 << 1 | b0 << 2 | r1 << 3 | g1 << 4 | b1 << 5
+        self.palette[..palette.len()].copy_from_slice(palette);
+        Ok(())
     }
 
-    pub fn render(&mut self, brightness: u8) {
+    // Copies a rectangle of pixels in `format` into the current frame, without
+    // waiting for a whole new frame. The position is in frame coordinates (see
+    // `Orientation::frame_size`) and `data` holds the rectangle row by row.
+    // Only the scan lines it touches are converted again on the next refresh.
+    pub fn update_rect(
+        &mut self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        data: &[u8],
+        format: PixelFormat,
+    ) -> Result<()> {
+        let (frame_width, frame_height) = self.orientation.frame_size();
+        if width == 0
+            || height == 0
+            || x + width > frame_width
+            || y + height > frame_height
+            || data.len() != width * height * format.bytes_per_pixel()
+        {
+            return Err(Error);
+        }
+
+        // A pending frame would overwrite the update when it is swapped in
         if self.swap_frames {
+            self.decode_next_frame();
+            self.swap_frames = false;
+            self.planes_stale = true;
+        }
+
+        let mut source = data.chunks_exact(format.bytes_per_pixel());
+        for row in y..y + height {
+            let start = 3 * (row * frame_width + x);
+            let pixels = self.current_frame[start..start + 3 * width].chunks_exact_mut(3);
+            for (pixel, data) in pixels.zip(source.by_ref()) {
+                pixel.copy_from_slice(&decode_pixel(format, &self.palette, data));
+            }
+        }
+
+        // Opposite corners of the rectangle are opposite corners on the panel
+        // as well, whatever the orientation.
+        let (_, y0) = self.orientation.panel_pixel(x, y);
+        let (_, y1) = self.orientation.panel_pixel(x + width - 1, y + height - 1);
+        for panel_y in y0.min(y1)..=y0.max(y1) {
+            self.dirty_lines |= 1 << (panel_y % HALF_HEIGHT);
+        }
+
+        Ok(())
+    }
+
+    fn decode_next_frame(&mut self) {
+        let format = self.next_format;
+        if format == PixelFormat::Rgb888 {
             self.current_frame.copy_from_slice(&self.next_frame);
+            return;
+        }
 
-            brightness_adjust(&mut self.current_frame, brightness);
-            self.swap_frames = false;
+        let source = self.next_frame.chunks_exact(format.bytes_per_pixel());
+        for (pixel, data) in self.current_frame.chunks_exact_mut(3).zip(source) {
+            pixel.copy_from_slice(&decode_pixel(format, &self.palette, data));
         }
-        for depth in 0..11 {
-            for row in 0..HEIGHT / 2 {
-                for col in 0..WIDTH {
-                    // Get the data from the current frame
-                    let data = self.get_data_bits(row, col, depth);
+    }
 
-                    // Set the data
-                    self.rgb_pins.set_rgb_bits(data).unwrap();
+    // Updates `line_sums` for the scan lines set in `lines`.
+    fn sum_lines(&mut self, lines: u32) {
+        let source_channels = self.panel.channel_order.source_channels();
+        for row in 0..HALF_HEIGHT {
+            if lines & (1 << row) == 0 {
+                continue;
+            }
 
-                    // Pulse the clock
-                    self.clock_pin.set_clock(false).unwrap();
-                    asm::nop();
-                    self.clock_pin.set_clock(true).unwrap();
+            let mut sums = [0u32; 3];
+            for col in 0..WIDTH {
+                for y in [row, row + HALF_HEIGHT] {
+                    let pixel = 3 * self.orientation.frame_pixel(col, y);
+                    for (sum, &channel) in sums.iter_mut().zip(source_channels.iter()) {
+                        *sum += gamma(channel, self.current_frame[pixel + channel]) as u32;
+                    }
                 }
+            }
+            self.line_sums[row] = sums;
+        }
+    }
 
-                // Set the address
-                self.addr_pins.set_addr_bits((row) as u8).unwrap();
+    // Average panel current at `brightness`: the duty cycle of every LED
+    // summed up, times the share of the refresh the delay table keeps the
+    // output enabled, split over the scan lines.
+    fn estimate_ma(&self, limit: &PowerLimit, brightness: u8) -> u32 {
+        let mut led_sum = 0u64;
+        for sums in self.line_sums.iter() {
+            for (&sum, &led_ma) in sums.iter().zip(limit.led_ma.iter()) {
+                led_sum += sum as u64 * led_ma as u64;
+            }
+        }
 
-                // Pulse the latch
-                self.latch_pin.set_latch(true).unwrap();
+        let on: u64 = self.delays(brightness)[..self.pwm_bits]
+            .iter()
+            .map(|&delay| delay as u64)
+            .sum();
+        let line_cycles = LINE_SHIFT_CYCLES * Self::ROWS_PER_SCAN as u64
+            + self.blank_before_address_cycles as u64
+            + self.blank_after_latch_cycles as u64;
+        let refresh = on + self.pwm_bits as u64 * line_cycles;
+        let scale = brightness_scale(brightness) as u64;
+
+        (led_sum * on * scale / (2047 * SCALE_FULL as u64 * refresh * Self::SCAN_LINES as u64))
+            as u32
+    }
+
+    // Picks the highest brightness up to `requested` that stays within the
+    // power budget.
+    fn limit_power(&mut self, requested: u8) -> u8 {
+        let Some(limit) = self.power_limit else {
+            self.power = PowerStatus {
+                estimate_ma: 0,
+                requested_brightness: requested,
+                brightness: requested,
+                limited: false,
+            };
+            return requested;
+        };
+
+        let mut brightness = requested.min(7);
+        let mut estimate_ma = self.estimate_ma(&limit, brightness);
+        while brightness > 0 && estimate_ma > limit.budget_ma {
+            brightness -= 1;
+            estimate_ma = self.estimate_ma(&limit, brightness);
+        }
+
+        self.power = PowerStatus {
+            estimate_ma,
+            requested_brightness: requested,
+            brightness,
+            limited: brightness < requested.min(7),
+        };
+        brightness
+    }
+
+    // Rebuilds the bitplanes from the current frame. Everything that does not
+    // change from one refresh to the next (gamma, orientation, channel order,
+    // data polarity) is done here so `render` only has to shift out bytes.
+    fn convert_frame(&mut self, brightness_scale: u16) {
+        for row in 0..HALF_HEIGHT {
+            self.convert_line(row, brightness_scale);
+        }
+
+        self.planes_brightness_scale = brightness_scale;
+        self.planes_stale = false;
+        self.dirty_lines = 0;
+        self.frame_black = self.current_frame.iter().all(|&value| value == 0);
+    }
+
+    // Rebuilds only the scan lines marked in `dirty_lines`.
+    fn convert_dirty_lines(&mut self) {
+        for row in 0..HALF_HEIGHT {
+            if self.dirty_lines & (1 << row) != 0 {
+                self.convert_line(row, self.planes_brightness_scale);
+            }
+        }
+
+        self.dirty_lines = 0;
+        self.frame_black = self.current_frame.iter().all(|&value| value == 0);
+    }
+
+    // Converts both panel rows shown on scan line `row`.
+    fn convert_line(&mut self, row: usize, brightness_scale: u16) {
+        let source_channels = self.panel.channel_order.source_channels();
+        let data_mask = if self.panel.invert_data {
+            0b0011_1111
+        } else {
+            0
+        };
+        let pwm_bits = self.pwm_bits;
+        let dither = self.dither;
+
+        for col in 0..WIDTH {
+            let top = 3 * self.orientation.frame_pixel(col, row);
+            let bottom = 3 * self.orientation.frame_pixel(col, row + HALF_HEIGHT);
+
+            let mut values = [0u16; 6];
+            let mut dither_levels = [0u8; 6];
+            for (input, &channel) in source_channels.iter().enumerate() {
+                for (bit, pixel) in [(input, top), (input + 3, bottom)] {
+                    let value = self.current_frame[pixel + channel];
+                    if dither {
+                        (values[bit], dither_levels[bit]) =
+                            dither_value(channel, value, brightness_scale, pwm_bits);
+                    } else {
+                        let value =
+                            (value as u32 * brightness_scale as u32 / SCALE_FULL as u32) as u8;
+                        values[bit] = reduce_depth(gamma(channel, value), pwm_bits);
+                    }
+                }
+            }
+
+            let index = row * WIDTH + col;
+            for (depth, plane) in self.planes[..pwm_bits].iter_mut().enumerate() {
+                let mut data = 0;
+                for (bit, value) in values.iter().enumerate() {
+                    data |= (((value >> depth) & 0x01) as u8) << bit;
+                }
+                plane[index] = data ^ data_mask;
+            }
+
+            if dither {
+                // Offset the cycle per pixel so the panel doesn't pulse as a whole
+                let offset = col + 3 * row;
+                for (phase, plane) in self.dither_planes.iter_mut().enumerate() {
+                    let threshold = DITHER_ORDER[(phase + offset) % DITHER_PHASES];
+                    let mut data = 0;
+                    for (bit, &level) in dither_levels.iter().enumerate() {
+                        data |= ((level > threshold) as u8) << bit;
+                    }
+                    plane[index] = data ^ data_mask;
+                }
+            }
+        }
+    }
+
+    // Sets a panel pixel to white or black in every bitplane.
+    fn set_plane_pixel(&mut self, x: usize, y: usize, lit: bool) {
+        let (index, bits) = if y < HALF_HEIGHT {
+            (y * WIDTH + x, 0b0000_0111)
+        } else {
+            ((y - HALF_HEIGHT) * WIDTH + x, 0b0011_1000)
+        };
+        let lit = lit != self.panel.invert_data;
+
+        for plane in self.planes.iter_mut().chain(self.dither_planes.iter_mut()) {
+            if lit {
+                plane[index] |= bits;
+            } else {
+                plane[index] &= !bits;
+            }
+        }
+    }
+
+    fn draw_refresh_overlay(&mut self) {
+        let orientation = self.orientation;
+        let refresh_hz = self.stats.refresh_hz.min(9999);
+
+        overlay::draw_number(refresh_hz, REFRESH_OVERLAY_DIGITS, |x, y, lit| {
+            let (x, y) = orientation.panel_pixel(x, y);
+            self.set_plane_pixel(x, y, lit);
+        });
+    }
+
+    // Shifts out every row on scan line `row`. Rows sharing a scan line are
+    // chained like panels in a row, the top one first.
+    fn shift_line(&mut self, depth: usize, dither_phase: usize, row: usize) {
+        let plane = if depth == 0 && self.dither {
+            &self.dither_planes[dither_phase]
+        } else {
+            &self.planes[depth]
+        };
+
+        for panel_row in (row..HALF_HEIGHT).step_by(Self::SCAN_LINES) {
+            for &data in &plane[panel_row * WIDTH..(panel_row + 1) * WIDTH] {
+                // Set the data
+                self.rgb_pins.set_rgb_bits(data).unwrap();
+
+                // Pulse the clock
+                self.clock_pin.set_clock(false).unwrap();
                 asm::nop();
-                self.latch_pin.set_latch(false).unwrap();
-
-                // Enable the output
-                self.output_enable_pin.set_output_enable(false).unwrap();
-
-                let delay_table;
-                match brightness {
-                    0 => delay_table = DELAY_TABLE_1,
-                    1 => delay_table = DELAY_TABLE_2,
-                    2 => delay_table = DELAY_TABLE_3,
-                    3 => delay_table = DELAY_TABLE_4,
-                    4 => delay_table = DELAY_TABLE_5,
-                    5 => delay_table = DELAY_TABLE_6,
-                    6 => delay_table = DELAY_TABLE_7,
-                    7 => delay_table = DELAY_TABLE_8,
-                    _ => delay_table = DELAY_TABLE_8,
+                self.clock_pin.set_clock(true).unwrap();
+            }
+        }
+    }
+
+    fn shift_black_line(&mut self) {
+        let black = if self.panel.invert_data {
+            0b0011_1111
+        } else {
+            0
+        };
+
+        for _ in 0..WIDTH * Self::ROWS_PER_SCAN {
+            self.rgb_pins.set_rgb_bits(black).unwrap();
+            self.clock_pin.set_clock(false).unwrap();
+            asm::nop();
+            self.clock_pin.set_clock(true).unwrap();
+        }
+    }
+
+    fn select_row(&mut self, row: usize) {
+        asm::delay(self.blank_before_address_cycles);
+        self.row_address.select_row(row).unwrap();
+    }
+
+    fn set_output_enable(&mut self, enabled: bool) {
+        // OE is active low unless the panel says otherwise
+        self.output_enable_pin
+            .set_output_enable(enabled == self.panel.invert_output_enable)
+            .unwrap();
+    }
+
+    fn pulse_latch(&mut self) {
+        let idle = self.panel.invert_latch;
+        self.latch_pin.set_latch(!idle).unwrap();
+        asm::nop();
+        self.latch_pin.set_latch(idle).unwrap();
+    }
+
+    // Waits until the next refresh is due when the refresh rate is limited and
+    // returns the start time of this refresh. The schedule uses the full 64 bit
+    // counter, so a long pause (idle, supply blanking) can't wrap it around.
+    fn wait_for_refresh(&mut self) -> u32 {
+        let now = self.timer.get_counter().ticks();
+        let Some(period) = self.refresh_period_us else {
+            self.stats.wait_us = 0;
+            return now as u32;
+        };
+
+        let period = period as u64;
+        let target = self.next_refresh_start;
+        if now < target {
+            while self.timer.get_counter().ticks() < target {}
+            self.stats.wait_us = (target - now) as u32;
+            self.next_refresh_start = target + period;
+            return target as u32;
+        }
+
+        // Running late. Stay on the schedule if this refresh can catch up,
+        // otherwise start a new one from here.
+        self.stats.wait_us = 0;
+        self.next_refresh_start = if now - target < period {
+            target + period
+        } else {
+            now + period
+        };
+        now as u32
+    }
+
+    // On time of each bitplane at `brightness`, in cycles.
+    fn delays(&self, brightness: u8) -> [u32; PWM_BITS] {
+        let delay_table = delay_table(brightness);
+
+        // With fewer bits the planes use the upper part of the delay table,
+        // shortened so the on time per refresh shrinks with the number of
+        // planes shifted out and the duty cycle stays the same.
+        let pwm_bits = self.pwm_bits;
+        let mut delays = [0u32; PWM_BITS];
+        for (delay, &table_delay) in delays
+            .iter_mut()
+            .zip(delay_table[PWM_BITS - pwm_bits..].iter())
+        {
+            *delay = table_delay * pwm_bits as u32 / PWM_BITS as u32;
+        }
+        if self.dither {
+            delays[0] *= 2;
+        }
+
+        delays
+    }
+
+    // Shows the current frame once. `brightness` goes from 0 to level 7 in
+    // steps of 1/LEVEL_STEPS of a level.
+    pub fn render(&mut self, brightness: u16) {
+        // Everything up to the scan out is done before waiting for the refresh,
+        // so converting a new frame doesn't delay it.
+        let convert_start = self.timer.get_counter_low();
+        if self.swap_frames {
+            self.decode_next_frame();
+            self.swap_frames = false;
+            self.planes_stale = true;
+        }
+
+        if self.power_limit.is_some() {
+            if self.planes_stale {
+                self.sum_lines(u32::MAX);
+            } else if self.dirty_lines != 0 {
+                self.sum_lines(self.dirty_lines);
+            }
+        }
+        // The power limit works in whole levels
+        let allowed = self.limit_power(brightness.div_ceil(LEVEL_STEPS) as u8);
+        let (level, stretch, brightness_scale) =
+            level_stretch(brightness.min(allowed as u16 * LEVEL_STEPS));
+
+        if self.planes_stale || brightness_scale != self.planes_brightness_scale {
+            self.convert_frame(brightness_scale);
+            self.stats.convert_us = self.timer.get_counter_low().wrapping_sub(convert_start);
+        } else if self.dirty_lines != 0 {
+            self.convert_dirty_lines();
+            self.stats.convert_us = self.timer.get_counter_low().wrapping_sub(convert_start);
+        } else {
+            self.stats.convert_us = 0;
+        }
+
+        if self.show_refresh {
+            self.draw_refresh_overlay();
+        }
+
+        let pwm_bits = self.pwm_bits;
+        let delays = self.delays(level).map(|delay| delay * stretch / 256);
+        let dither_phase = self.dither_phase;
+        self.dither_phase = (self.dither_phase + 1) % DITHER_PHASES;
+
+        let schedule = ScanSchedule::new(&delays[..pwm_bits], self.plane_splits);
+        self.stats.plane_us = [0; PWM_BITS];
+
+        let start = self.wait_for_refresh();
+        if let Some(last_start) = self.last_render_start {
+            self.stats.frame_us = start.wrapping_sub(last_start);
+            self.stats.refresh_hz = 1_000_000 / self.stats.frame_us.max(1);
+        }
+        self.last_render_start = Some(start);
+
+        let mut segment_start = self.timer.get_counter_low();
+        for &(depth, delay) in schedule.segments() {
+            for row in 0..Self::SCAN_LINES {
+                if self.panel.precharge_black_line {
+                    self.shift_black_line();
+                    self.pulse_latch();
+                    self.select_row(row);
+                    self.shift_line(depth, dither_phase, row);
+                } else {
+                    self.shift_line(depth, dither_phase, row);
+                    self.select_row(row);
                 }
 
-                asm::delay(delay_table[depth as usize]);
-                self.output_enable_pin.set_output_enable(true).unwrap();
+                // Pulse the latch
+                self.pulse_latch();
+                asm::delay(self.blank_after_latch_cycles);
+
+                // Enable the output. Interrupts wait until it is off again, so
+                // they can't stretch the on time.
+                cortex_m::interrupt::free(|_| {
+                    self.set_output_enable(true);
+                    asm::delay(delay);
+                    self.set_output_enable(false);
+                });
+            }
+
+            let segment_end = self.timer.get_counter_low();
+            self.stats.plane_us[depth] += segment_end.wrapping_sub(segment_start);
+            segment_start = segment_end;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn orientations() -> impl Iterator<Item = Orientation> {
+        [
+            Rotation::Deg0,
+            Rotation::Deg90,
+            Rotation::Deg180,
+            Rotation::Deg270,
+        ]
+        .into_iter()
+        .flat_map(|rotation| {
+            [(false, false), (true, false), (false, true), (true, true)].map(
+                |(mirror_horizontal, mirror_vertical)| Orientation {
+                    rotation,
+                    mirror_horizontal,
+                    mirror_vertical,
+                },
+            )
+        })
+    }
+
+    #[test]
+    fn panel_pixel_undoes_frame_pixel() {
+        for orientation in orientations() {
+            let (frame_width, _) = orientation.frame_size();
+            for y in 0..HEIGHT {
+                for x in 0..WIDTH {
+                    let index = orientation.frame_pixel(x, y);
+                    assert!(index < WIDTH * HEIGHT, "{:?}", orientation);
+                    assert_eq!(
+                        orientation.panel_pixel(index % frame_width, index / frame_width),
+                        (x, y),
+                        "{:?}",
+                        orientation
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn frame_pixel_undoes_panel_pixel() {
+        for orientation in orientations() {
+            let (frame_width, frame_height) = orientation.frame_size();
+            for frame_y in 0..frame_height {
+                for frame_x in 0..frame_width {
+                    let (x, y) = orientation.panel_pixel(frame_x, frame_y);
+                    assert!(x < WIDTH && y < HEIGHT, "{:?}", orientation);
+                    assert_eq!(
+                        orientation.frame_pixel(x, y),
+                        frame_y * frame_width + frame_x,
+                        "{:?}",
+                        orientation
+                    );
+                }
             }
         }
     }
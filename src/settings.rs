@@ -0,0 +1,392 @@
+// Path: src/settings.rs
+//
+// Settings that survive a reset, kept in the last flash sector. The record is
+//
+//   magic, version, payload length (u16), payload, FNV-1a hash of the payload
+//
+// all little endian. Fields are only ever appended to the payload, and fields
+// missing from an older record keep their defaults.
+use crate::ambient::{Calibration, CalibrationPoint};
+use crate::brightness::{
+    BrightnessConfig, Curve, Mode, Schedule, ScheduleAction, ScheduleEntry, SCHEDULE_ENTRIES,
+};
+use crate::flash;
+
+const MAGIC: u32 = 0x534D_4C52;
+const VERSION: u8 = 1;
+const HEADER: usize = 7;
+const HASH: usize = 4;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Settings {
+    pub brightness: BrightnessConfig,
+    pub calibration: Calibration,
+    pub mode: Mode,
+    pub schedule: Schedule,
+}
+
+impl Settings {
+    // The stored settings, or the defaults if there are none or they are damaged.
+    pub fn load() -> Settings {
+        let record = flash::read(flash::SETTINGS_OFFSET, flash::PAGE_SIZE);
+        decode_record(record).unwrap_or_default()
+    }
+
+    // Writes the settings to flash, unless they are stored already.
+    pub fn save(&self) {
+        let mut record = [0xFF; flash::PAGE_SIZE];
+        encode_record(self, &mut record);
+
+        if flash::read(flash::SETTINGS_OFFSET, flash::PAGE_SIZE) != record {
+            flash::write(flash::SETTINGS_OFFSET, &record);
+        }
+    }
+}
+
+fn fnv1a(data: &[u8]) -> u32 {
+    data.iter().fold(0x811C_9DC5, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+fn encode_record(settings: &Settings, record: &mut [u8]) {
+    let mut writer = Writer::new(&mut record[HEADER..]);
+    encode_brightness(&settings.brightness, &mut writer);
+    encode_calibration(&settings.calibration, &mut writer);
+    encode_mode(&settings.mode, &mut writer);
+    encode_schedule(&settings.schedule, &mut writer);
+    writer.f32(settings.brightness.ramp_s);
+    let length = writer.position;
+    finish_record(record, length);
+}
+
+// Writes the header and the hash around a payload of `length` bytes.
+fn finish_record(record: &mut [u8], length: usize) {
+    record[..4].copy_from_slice(&MAGIC.to_le_bytes());
+    record[4] = VERSION;
+    record[5..HEADER].copy_from_slice(&(length as u16).to_le_bytes());
+    let hash = fnv1a(&record[HEADER..HEADER + length]);
+    record[HEADER + length..HEADER + length + HASH].copy_from_slice(&hash.to_le_bytes());
+}
+
+fn decode_record(record: &[u8]) -> Option<Settings> {
+    let magic = u32::from_le_bytes(record[..4].try_into().ok()?);
+    let length = u16::from_le_bytes([record[5], record[6]]) as usize;
+    if magic != MAGIC || record[4] != VERSION || HEADER + length + HASH > record.len() {
+        return None;
+    }
+
+    let payload = &record[HEADER..HEADER + length];
+    let hash = &record[HEADER + length..HEADER + length + HASH];
+    if fnv1a(payload).to_le_bytes() != hash {
+        return None;
+    }
+
+    let mut reader = Reader::new(payload);
+    let defaults = Settings::default();
+    let mut settings = Settings {
+        brightness: decode_brightness(&mut reader).unwrap_or(defaults.brightness),
+        calibration: decode_calibration(&mut reader).unwrap_or(defaults.calibration),
+        mode: decode_mode(&mut reader).unwrap_or(defaults.mode),
+        schedule: decode_schedule(&mut reader).unwrap_or(defaults.schedule),
+    };
+    if let Some(ramp_s) = reader.f32() {
+        settings.brightness.ramp_s = ramp_s;
+    }
+    Some(settings)
+}
+
+// Brightness settings as stored at the start of the record. The ramp time came
+// later and is stored at the end of the record instead.
+fn encode_brightness(config: &BrightnessConfig, writer: &mut Writer) {
+    let (kind, values) = match config.curve {
+        Curve::Steps(thresholds) => (0, thresholds),
+        Curve::Logarithmic { dark, bright } => (1, [dark, bright, 0, 0, 0, 0, 0]),
+    };
+    writer.u8(kind);
+    for value in values {
+        writer.u32(value);
+    }
+    writer.u8(config.hysteresis_percent);
+    writer.u8(config.min_level);
+    writer.u8(config.max_level);
+    writer.f32(config.smoothing_s);
+    writer.u8(config.fault_level);
+    writer.u16(config.fault_timeout_s);
+}
+
+fn decode_brightness(reader: &mut Reader) -> Option<BrightnessConfig> {
+    let kind = reader.u8()?;
+    let mut values = [0; 7];
+    for value in values.iter_mut() {
+        *value = reader.u32()?;
+    }
+    let curve = match kind {
+        0 => Curve::Steps(values),
+        1 => Curve::Logarithmic {
+            dark: values[0],
+            bright: values[1],
+        },
+        _ => return None,
+    };
+
+    let defaults = BrightnessConfig::default();
+    Some(BrightnessConfig {
+        curve,
+        hysteresis_percent: reader.u8()?,
+        min_level: reader.u8()?,
+        max_level: reader.u8()?,
+        smoothing_s: reader.f32()?,
+        // Added later, older messages end before these
+        fault_level: reader.u8().unwrap_or(defaults.fault_level),
+        fault_timeout_s: reader.u16().unwrap_or(defaults.fault_timeout_s),
+        ramp_s: defaults.ramp_s,
+    })
+}
+
+// Payload of the brightness config message: the brightness settings as in the
+// record, then the ramp time (f32), which older senders leave out.
+pub fn decode_brightness_message(reader: &mut Reader) -> Option<BrightnessConfig> {
+    let mut config = decode_brightness(reader)?;
+    if let Some(ramp_s) = reader.f32() {
+        config.ramp_s = ramp_s;
+    }
+    Some(config)
+}
+
+// Each reference reading is a flag for whether it was taken, then the raw
+// reading and the lux it stands for.
+fn encode_calibration(calibration: &Calibration, writer: &mut Writer) {
+    for point in [calibration.dark, calibration.bright] {
+        let CalibrationPoint { raw, lux } =
+            point.unwrap_or(CalibrationPoint { raw: 0.0, lux: 0.0 });
+        writer.u8(point.is_some() as u8);
+        writer.f32(raw);
+        writer.f32(lux);
+    }
+}
+
+fn decode_calibration(reader: &mut Reader) -> Option<Calibration> {
+    let mut points = [None; 2];
+    for point in points.iter_mut() {
+        let taken = reader.u8()? != 0;
+        let raw = reader.f32()?;
+        let lux = reader.f32()?;
+        *point = taken.then_some(CalibrationPoint { raw, lux });
+    }
+
+    let [dark, bright] = points;
+    Some(Calibration { dark, bright })
+}
+
+// Brightness mode, also the payload of the brightness mode message: the kind,
+// then the manual level or the offset (i8).
+pub fn encode_mode(mode: &Mode, writer: &mut Writer) {
+    let (kind, value) = match *mode {
+        Mode::Auto => (0, 0),
+        Mode::Manual(level) => (1, level),
+        Mode::AutoOffset(offset) => (2, offset as u8),
+    };
+    writer.u8(kind);
+    writer.u8(value);
+}
+
+pub fn decode_mode(reader: &mut Reader) -> Option<Mode> {
+    let kind = reader.u8()?;
+    let value = reader.u8()?;
+    match kind {
+        0 => Some(Mode::Auto),
+        1 => Some(Mode::Manual(value)),
+        2 => Some(Mode::AutoOffset(value as i8)),
+        _ => None,
+    }
+}
+
+// Brightness schedule, also the payload of the schedule message: the number of
+// entries, then for each the start and end minute (u16), the action (0 to cap,
+// 1 to fix) and the level.
+pub fn encode_schedule(schedule: &Schedule, writer: &mut Writer) {
+    writer.u8(schedule.iter().flatten().count() as u8);
+    for entry in schedule.iter().flatten() {
+        let (action, level) = match entry.action {
+            ScheduleAction::Cap(level) => (0, level),
+            ScheduleAction::Fix(level) => (1, level),
+        };
+        writer.u16(entry.start_minute);
+        writer.u16(entry.end_minute);
+        writer.u8(action);
+        writer.u8(level);
+    }
+}
+
+pub fn decode_schedule(reader: &mut Reader) -> Option<Schedule> {
+    let count = reader.u8()? as usize;
+    if count > SCHEDULE_ENTRIES {
+        return None;
+    }
+
+    let mut schedule = [None; SCHEDULE_ENTRIES];
+    for entry in schedule.iter_mut().take(count) {
+        let start_minute = reader.u16()?;
+        let end_minute = reader.u16()?;
+        let action = match (reader.u8()?, reader.u8()?) {
+            (0, level) => ScheduleAction::Cap(level),
+            (1, level) => ScheduleAction::Fix(level),
+            _ => return None,
+        };
+        if start_minute >= 24 * 60 || end_minute >= 24 * 60 {
+            return None;
+        }
+        *entry = Some(ScheduleEntry {
+            start_minute,
+            end_minute,
+            action,
+        });
+    }
+    Some(schedule)
+}
+
+pub struct Writer<'a> {
+    buffer: &'a mut [u8],
+    position: usize,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Writer<'a> {
+        Writer {
+            buffer,
+            position: 0,
+        }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buffer[self.position..self.position + bytes.len()].copy_from_slice(bytes);
+        self.position += bytes.len();
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes(&[value]);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn f32(&mut self, value: f32) {
+        self.bytes(&value.to_le_bytes());
+    }
+}
+
+pub struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data }
+    }
+
+    fn bytes<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let (bytes, rest) = self.data.split_first_chunk::<N>()?;
+        self.data = rest;
+        Some(*bytes)
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        self.bytes::<1>().map(|[value]| value)
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        self.bytes().map(u16::from_le_bytes)
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        self.bytes().map(u32::from_le_bytes)
+    }
+
+    pub fn f32(&mut self) -> Option<f32> {
+        self.bytes().map(f32::from_le_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brightness::Curve;
+
+    fn settings() -> Settings {
+        let mut schedule = [None; SCHEDULE_ENTRIES];
+        schedule[0] = Some(ScheduleEntry {
+            start_minute: 22 * 60,
+            end_minute: 6 * 60,
+            action: ScheduleAction::Cap(2),
+        });
+
+        Settings {
+            brightness: BrightnessConfig {
+                curve: Curve::Steps([0, 10, 20, 40, 80, 160, 320]),
+                hysteresis_percent: 5,
+                min_level: 2,
+                max_level: 6,
+                smoothing_s: 0.5,
+                fault_level: 3,
+                fault_timeout_s: 10,
+                ramp_s: 0.25,
+            },
+            calibration: Calibration {
+                dark: Some(CalibrationPoint {
+                    raw: 12.0,
+                    lux: 1.0,
+                }),
+                bright: Some(CalibrationPoint {
+                    raw: 3000.0,
+                    lux: 800.0,
+                }),
+            },
+            mode: Mode::AutoOffset(-1),
+            schedule,
+        }
+    }
+
+    #[test]
+    fn record_round_trips() {
+        let mut record = [0xFF; flash::PAGE_SIZE];
+        encode_record(&settings(), &mut record);
+        assert_eq!(decode_record(&record), Some(settings()));
+    }
+
+    #[test]
+    fn record_without_ramp_time_keeps_the_other_fields() {
+        let settings = settings();
+        let mut record = [0xFF; flash::PAGE_SIZE];
+        let mut writer = Writer::new(&mut record[HEADER..]);
+        encode_brightness(&settings.brightness, &mut writer);
+        encode_calibration(&settings.calibration, &mut writer);
+        encode_mode(&settings.mode, &mut writer);
+        encode_schedule(&settings.schedule, &mut writer);
+        let length = writer.position;
+        finish_record(&mut record, length);
+
+        let mut expected = settings;
+        expected.brightness.ramp_s = BrightnessConfig::default().ramp_s;
+        assert_eq!(decode_record(&record), Some(expected));
+    }
+
+    #[test]
+    fn message_without_ramp_time_uses_the_default() {
+        let mut payload = [0; 64];
+        let mut writer = Writer::new(&mut payload);
+        encode_brightness(&settings().brightness, &mut writer);
+        let length = writer.position;
+
+        let config = decode_brightness_message(&mut Reader::new(&payload[..length]));
+        assert_eq!(
+            config.map(|config| config.ramp_s),
+            Some(BrightnessConfig::default().ramp_s)
+        );
+    }
+}
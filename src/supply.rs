@@ -0,0 +1,68 @@
+// Path: src/supply.rs
+//
+// Protection for units running from a battery or a weak supply. When the
+// supply voltage drops below a threshold the brightness is capped one level
+// lower for every step further down, so the panel draws less before the rail
+// sags far enough to brown out the RP2040. Below the critical voltage the panel
+// is blanked. Each step is undone once the voltage has recovered past its
+// threshold by the hysteresis, which has to be more than the supply sags under
+// the panel load, otherwise the panel toggles.
+use crate::brightness::MAX_LEVEL;
+
+#[derive(Clone, Copy, Debug)]
+pub struct SupplyLimit {
+    // The brightness is capped one level lower for every `step_v` below
+    // `derate_v`.
+    pub derate_v: f32,
+    pub step_v: f32,
+    // Below this the panel is blanked.
+    pub critical_v: f32,
+    pub hysteresis_v: f32,
+}
+
+pub struct SupplyGuard {
+    limit: SupplyLimit,
+    // Levels taken off the brightness.
+    derate_steps: u8,
+    blank: bool,
+}
+
+impl SupplyGuard {
+    pub fn new(limit: SupplyLimit) -> SupplyGuard {
+        SupplyGuard {
+            limit,
+            derate_steps: 0,
+            blank: false,
+        }
+    }
+
+    pub fn update(&mut self, volts: f32) {
+        let limit = &self.limit;
+        let threshold = |steps: u8| limit.derate_v - steps as f32 * limit.step_v.max(0.0);
+
+        while self.derate_steps < MAX_LEVEL && volts < threshold(self.derate_steps) {
+            self.derate_steps += 1;
+        }
+        while self.derate_steps > 0
+            && volts >= threshold(self.derate_steps - 1) + limit.hysteresis_v
+        {
+            self.derate_steps -= 1;
+        }
+
+        if volts < limit.critical_v {
+            self.blank = true;
+        } else if volts >= limit.critical_v + limit.hysteresis_v {
+            self.blank = false;
+        }
+    }
+
+    // Highest brightness level allowed at the current voltage.
+    pub fn max_level(&self) -> u8 {
+        MAX_LEVEL - self.derate_steps
+    }
+
+    // Whether the panel has to be blanked.
+    pub fn blank(&self) -> bool {
+        self.blank
+    }
+}
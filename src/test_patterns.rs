@@ -0,0 +1,130 @@
+// Path: src/test_patterns.rs
+//
+// Frames for commissioning a panel without a host: wiring, address lines,
+// colour order and the gamma tables can all be checked by eye.
+use crate::framebuffer::FrameBuffer;
+use embedded_graphics::pixelcolor::{Rgb888, RgbColor};
+use embedded_graphics::prelude::{DrawTarget, OriginDimensions, Point, Size};
+use embedded_graphics::primitives::Rectangle;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum TestPattern {
+    // White, yellow, cyan, green, magenta, red, blue and black bars.
+    ColorBars,
+    // Red, green, blue and white ramps from black to full, one band each.
+    Ramps,
+    // 16 steps per channel, to check the gamma curves for even steps.
+    GammaSteps,
+    Checkerboard,
+    // A single white pixel moving across the panel in reading order.
+    MovingPixel,
+    // One lit row moving down, a stuck address line shows up as rows skipped
+    // or lit twice.
+    RowWalk,
+    ColumnWalk,
+    // Every LED fully on, for burn in and power supply testing.
+    FullWhite,
+}
+
+impl TestPattern {
+    pub fn from_u8(value: u8) -> Option<TestPattern> {
+        match value {
+            0 => Some(TestPattern::ColorBars),
+            1 => Some(TestPattern::Ramps),
+            2 => Some(TestPattern::GammaSteps),
+            3 => Some(TestPattern::Checkerboard),
+            4 => Some(TestPattern::MovingPixel),
+            5 => Some(TestPattern::RowWalk),
+            6 => Some(TestPattern::ColumnWalk),
+            7 => Some(TestPattern::FullWhite),
+            _ => None,
+        }
+    }
+
+    // The pattern after this one, wrapping around.
+    pub fn next(self) -> TestPattern {
+        TestPattern::from_u8(self as u8 + 1).unwrap_or(TestPattern::ColorBars)
+    }
+}
+
+const BARS: [Rgb888; 8] = [
+    Rgb888::WHITE,
+    Rgb888::YELLOW,
+    Rgb888::CYAN,
+    Rgb888::GREEN,
+    Rgb888::MAGENTA,
+    Rgb888::RED,
+    Rgb888::BLUE,
+    Rgb888::BLACK,
+];
+const BANDS: [Rgb888; 4] = [Rgb888::RED, Rgb888::GREEN, Rgb888::BLUE, Rgb888::WHITE];
+const GAMMA_STEPS: u32 = 16;
+
+fn fill(frame: &mut FrameBuffer, x: u32, y: u32, width: u32, height: u32, color: Rgb888) {
+    let area = Rectangle::new(Point::new(x as i32, y as i32), Size::new(width, height));
+    frame.fill_solid(&area, color).unwrap();
+}
+
+// Scales a full colour band to `level` out of 255.
+fn band_color(band: Rgb888, level: u32) -> Rgb888 {
+    let scale = |value: u8| (value as u32 * level / 255) as u8;
+    Rgb888::new(scale(band.r()), scale(band.g()), scale(band.b()))
+}
+
+// Draws `pattern` over the whole frame. `step` counts up from 0 for the
+// animated patterns.
+pub fn draw(pattern: TestPattern, step: u32, frame: &mut FrameBuffer) {
+    let Size { width, height } = frame.size();
+    frame.clear(Rgb888::BLACK).unwrap();
+
+    match pattern {
+        TestPattern::ColorBars => {
+            let count = BARS.len() as u32;
+            for (bar, &color) in BARS.iter().enumerate() {
+                let left = bar as u32 * width / count;
+                let right = (bar as u32 + 1) * width / count;
+                fill(frame, left, 0, right - left, height, color);
+            }
+        }
+        TestPattern::Ramps | TestPattern::GammaSteps => {
+            let count = BANDS.len() as u32;
+            for (band, &color) in BANDS.iter().enumerate() {
+                let top = band as u32 * height / count;
+                let bottom = (band as u32 + 1) * height / count;
+                for x in 0..width {
+                    let level = if pattern == TestPattern::Ramps {
+                        x * 255 / (width - 1)
+                    } else {
+                        (x * GAMMA_STEPS / width) * 255 / (GAMMA_STEPS - 1)
+                    };
+                    fill(frame, x, top, 1, bottom - top, band_color(color, level));
+                }
+            }
+        }
+        TestPattern::Checkerboard => {
+            // Swap the squares every 10 steps so every LED gets to light up
+            let phase = step / 10;
+            for y in 0..height {
+                for x in 0..width {
+                    if (x + y + phase).is_multiple_of(2) {
+                        fill(frame, x, y, 1, 1, Rgb888::WHITE);
+                    }
+                }
+            }
+        }
+        TestPattern::MovingPixel => {
+            let position = step % (width * height);
+            fill(
+                frame,
+                position % width,
+                position / width,
+                1,
+                1,
+                Rgb888::WHITE,
+            );
+        }
+        TestPattern::RowWalk => fill(frame, 0, step % height, width, 1, Rgb888::WHITE),
+        TestPattern::ColumnWalk => fill(frame, step % width, 0, 1, height, Rgb888::WHITE),
+        TestPattern::FullWhite => frame.clear(Rgb888::WHITE).unwrap(),
+    }
+}
@@ -0,0 +1,67 @@
+// Path: src/thermal.rs
+//
+// Protection against overheating in closed enclosures. Above a temperature the
+// brightness is capped one level lower for every few degrees, and above
+// another one the system clock is halved as well. Each step is undone once the
+// temperature has dropped below its threshold by the hysteresis.
+use crate::brightness::MAX_LEVEL;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ThermalLimit {
+    // The brightness is capped one level lower for every `step_c` above
+    // `derate_c`.
+    pub derate_c: f32,
+    pub step_c: f32,
+    // Above this the system clock is halved, None to leave it alone.
+    pub slow_clock_c: Option<f32>,
+    pub hysteresis_c: f32,
+}
+
+pub struct ThermalGuard {
+    limit: ThermalLimit,
+    // Levels taken off the brightness.
+    derate_steps: u8,
+    slow_clock: bool,
+}
+
+impl ThermalGuard {
+    pub fn new(limit: ThermalLimit) -> ThermalGuard {
+        ThermalGuard {
+            limit,
+            derate_steps: 0,
+            slow_clock: false,
+        }
+    }
+
+    pub fn update(&mut self, temperature_c: f32) {
+        let limit = &self.limit;
+        let threshold = |steps: u8| limit.derate_c + steps as f32 * limit.step_c.max(0.0);
+
+        while self.derate_steps < MAX_LEVEL && temperature_c >= threshold(self.derate_steps) {
+            self.derate_steps += 1;
+        }
+        while self.derate_steps > 0
+            && temperature_c < threshold(self.derate_steps - 1) - limit.hysteresis_c
+        {
+            self.derate_steps -= 1;
+        }
+
+        if let Some(slow_clock_c) = limit.slow_clock_c {
+            if temperature_c >= slow_clock_c {
+                self.slow_clock = true;
+            } else if temperature_c < slow_clock_c - limit.hysteresis_c {
+                self.slow_clock = false;
+            }
+        }
+    }
+
+    // Highest brightness level allowed at the current temperature.
+    pub fn max_level(&self) -> u8 {
+        MAX_LEVEL - self.derate_steps
+    }
+
+    // Whether the system clock should be halved.
+    pub fn slow_clock(&self) -> bool {
+        self.slow_clock
+    }
+}